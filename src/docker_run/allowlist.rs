@@ -0,0 +1,326 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::docker_run::docker;
+use crate::docker_run::http_extra;
+use crate::docker_run::unix_stream;
+
+// An empty allowlist allows all images
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub pattern: String,
+    pub digest: Option<String>,
+}
+
+// Entries are written as <pattern>[@<digest>], i.e. glot/*:latest or glot/python:latest@sha256:...
+impl FromStr for Entry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, digest) = match s.split_once('@') {
+            Some((pattern, digest)) => (pattern, Some(digest)),
+            None => (s, None),
+        };
+
+        if pattern.is_empty() {
+            return Err(format!("Missing image pattern in allowlist entry: {}", s));
+        }
+
+        if let Some("") = digest {
+            return Err(format!("Missing digest in allowlist entry: {}", s));
+        }
+
+        Ok(Entry {
+            pattern: pattern.to_string(),
+            digest: digest.map(|digest| digest.to_string()),
+        })
+    }
+}
+
+impl Config {
+    pub fn is_enabled(&self) -> bool {
+        !self.entries.is_empty()
+    }
+
+    // A digest requested by the client is compared with the pinned digest, not the pattern
+    pub fn find_entry(&self, image: &str) -> Option<&Entry> {
+        let image = normalize_image_name(image.split('@').next().unwrap_or(image));
+
+        self.entries
+            .iter()
+            .find(|entry| glob_match(&entry.pattern, &image))
+    }
+}
+
+// Returns the image reference to pull and run. Images pinned to a digest are
+// referenced by digest, i.e. glot/python@sha256:..., so that the pinned image is
+// pulled instead of whatever the tag points to.
+pub fn check(config: &Config, image: &str) -> Result<String, Error> {
+    if !config.is_enabled() {
+        return Ok(image.to_string());
+    }
+
+    let entry = config
+        .find_entry(image)
+        .ok_or_else(|| Error::NotAllowed(image.to_string()))?;
+
+    match &entry.digest {
+        Some(digest) => {
+            // The client can also ask for a digest, it must be the pinned one
+            let has_other_digest = image
+                .split_once('@')
+                .is_some_and(|(_, image_digest)| image_digest != digest);
+
+            if has_other_digest {
+                return Err(Error::DigestMismatch {
+                    image: image.to_string(),
+                    digest: digest.to_string(),
+                });
+            }
+
+            Ok(format!("{}@{}", repository(image), digest))
+        }
+
+        None => Ok(image.to_string()),
+    }
+}
+
+// Verifies that the local image has the pinned digest, must be called after the image is pulled
+pub fn verify_digest(
+    config: &Config,
+    stream_config: &unix_stream::Config,
+    image: &str,
+) -> Result<(), Error> {
+    let digest = match config
+        .find_entry(image)
+        .and_then(|entry| entry.digest.as_ref())
+    {
+        Some(digest) => digest,
+        None => return Ok(()),
+    };
+
+    let reference = format!("{}@{}", repository(image), digest);
+
+    let result = unix_stream::with_stream(stream_config, Error::UnixStream, |stream| {
+        docker::inspect_image(stream, &reference).map_err(Error::InspectImage)
+    });
+
+    let has_digest = match result {
+        Ok(response) => response
+            .body()
+            .repo_digests
+            .iter()
+            .any(|repo_digest| repo_digest.ends_with(&format!("@{}", digest))),
+
+        // The image has not been pulled with the pinned digest
+        Err(Error::InspectImage(docker::Error::SendRequest(http_extra::Error::BadStatus(
+            status,
+            _,
+        )))) if status == http::StatusCode::NOT_FOUND => false,
+
+        Err(err) => return Err(err),
+    };
+
+    if has_digest {
+        Ok(())
+    } else {
+        Err(Error::DigestMismatch {
+            image: image.to_string(),
+            digest: digest.to_string(),
+        })
+    }
+}
+
+// The image name without tag or digest, i.e. glot/python for glot/python:latest.
+// A registry can have a port, i.e. localhost:5000/glot/python.
+pub fn repository(image: &str) -> &str {
+    let image = image.split('@').next().unwrap_or(image);
+
+    match image.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => repository,
+        _ => image,
+    }
+}
+
+// Docker uses the latest tag when no tag or digest is given
+pub fn normalize_image_name(image: &str) -> String {
+    let name = image.rsplit('/').next().unwrap_or(image);
+
+    if name.contains(':') || name.contains('@') {
+        image.to_string()
+    } else {
+        format!("{}:latest", image)
+    }
+}
+
+// Matches text against a pattern where * matches any sequence of characters except /
+// and ? matches one character, i.e. glot/* doesn't match glot/x/python
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let mut p = 0;
+    let mut t = 0;
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            // A star can't match across a path separator
+            if text[star_t] == '/' {
+                return false;
+            }
+
+            // Let the last star consume one more character and retry
+            backtrack = Some((star_p, star_t + 1));
+            p = star_p + 1;
+            t = star_t + 1;
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[derive(Debug)]
+pub enum Error {
+    NotAllowed(String),
+    DigestMismatch { image: String, digest: String },
+    UnixStream(unix_stream::Error),
+    InspectImage(docker::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotAllowed(image) => {
+                write!(f, "Image is not allowed: {}", image)
+            }
+
+            Error::DigestMismatch { image, digest } => {
+                write!(
+                    f,
+                    "Image {} does not have the required digest: {}",
+                    image, digest
+                )
+            }
+
+            Error::UnixStream(err) => {
+                write!(f, "Unix socket failure: {}", err)
+            }
+
+            Error::InspectImage(err) => {
+                write!(f, "Failed to inspect image: {}", err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(entries: &[&str]) -> Config {
+        Config {
+            entries: entries.iter().map(|entry| entry.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn glob_match_star_stops_at_slash() {
+        assert!(glob_match("glot/*", "glot/python:latest"));
+        assert!(glob_match("glot/*:latest", "glot/python:latest"));
+        assert!(glob_match("*/python:latest", "glot/python:latest"));
+        assert!(!glob_match("glot/*", "glot/x/python:latest"));
+        assert!(!glob_match("glot/*:latest", "glot/python:3.12"));
+        assert!(!glob_match("*", "glot/python:latest"));
+    }
+
+    #[test]
+    fn glob_match_question_mark_and_literals() {
+        assert!(glob_match("glot/python:3.1?", "glot/python:3.12"));
+        assert!(!glob_match("glot/python:3.1?", "glot/python:3.1"));
+        assert!(glob_match("glot/python:latest", "glot/python:latest"));
+        assert!(!glob_match("glot/python:latest", "glot/python:latest2"));
+    }
+
+    #[test]
+    fn parse_entry() {
+        let entry: Entry = "glot/python:latest@sha256:abc".parse().unwrap();
+        assert_eq!(entry.pattern, "glot/python:latest");
+        assert_eq!(entry.digest.as_deref(), Some("sha256:abc"));
+
+        assert!("@sha256:abc".parse::<Entry>().is_err());
+        assert!("glot/python@".parse::<Entry>().is_err());
+    }
+
+    #[test]
+    fn find_entry_normalizes_the_tag() {
+        let config = config(&["glot/*:latest"]);
+
+        assert!(config.find_entry("glot/python").is_some());
+        assert!(config.find_entry("glot/python:latest").is_some());
+        assert!(config.find_entry("glot/python:3.12").is_none());
+    }
+
+    #[test]
+    fn check_pinned_digest() {
+        let config = config(&["glot/python:latest@sha256:abc", "glot/*:latest"]);
+
+        assert_eq!(
+            check(&config, "glot/python:latest").unwrap(),
+            "glot/python@sha256:abc"
+        );
+        assert_eq!(
+            check(&config, "glot/rust:latest").unwrap(),
+            "glot/rust:latest"
+        );
+        assert!(matches!(
+            check(&config, "glot/python:latest@sha256:def"),
+            Err(Error::DigestMismatch { .. })
+        ));
+        assert_eq!(
+            check(&config, "glot/python:latest@sha256:abc").unwrap(),
+            "glot/python@sha256:abc"
+        );
+        assert!(matches!(
+            check(&config, "other/python:latest"),
+            Err(Error::NotAllowed(_))
+        ));
+    }
+
+    #[test]
+    fn check_allows_all_images_when_disabled() {
+        let config = config(&[]);
+
+        assert_eq!(check(&config, "any/image").unwrap(), "any/image");
+    }
+
+    #[test]
+    fn repository_and_normalized_name() {
+        assert_eq!(repository("glot/python:latest"), "glot/python");
+        assert_eq!(repository("glot/python@sha256:abc"), "glot/python");
+        assert_eq!(
+            repository("localhost:5000/glot/python"),
+            "localhost:5000/glot/python"
+        );
+
+        assert_eq!(normalize_image_name("glot/python"), "glot/python:latest");
+        assert_eq!(normalize_image_name("glot/python:3.12"), "glot/python:3.12");
+        assert_eq!(
+            normalize_image_name("localhost:5000/glot/python"),
+            "localhost:5000/glot/python:latest"
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::sync::atomic::Ordering;
+
+use crate::docker_run::api;
+use crate::docker_run::config;
+use crate::docker_run::docker;
+use crate::docker_run::runtime;
+use crate::docker_run::unix_stream;
+
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub required_images: Vec<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct HealthStatus {
+    status: String,
+}
+
+// The process is alive if it can answer requests
+pub fn handle_liveness() -> Result<api::SuccessResponse, api::ErrorResponse> {
+    prepare_status_response("alive")
+}
+
+// The service is ready if it's not shutting down, the startup image pulls are done,
+// the docker daemon answers and all required images exist
+pub fn handle_readiness(
+    config: &config::Config,
+    runtime: &runtime::Runtime,
+) -> Result<api::SuccessResponse, api::ErrorResponse> {
+    err_if_false(!runtime.queue.is_closed(), Error::ShuttingDown()).map_err(handle_error)?;
+
+    let images_ready = runtime.images_ready.load(Ordering::SeqCst);
+    err_if_false(images_ready, Error::PullingImages()).map_err(handle_error)?;
+
+    check_readiness(&config.unix_socket, &config.health).map_err(handle_error)?;
+
+    prepare_status_response("ready")
+}
+
+fn check_readiness(stream_config: &unix_stream::Config, config: &Config) -> Result<(), Error> {
+    unix_stream::with_stream(stream_config, Error::UnixStream, |stream| {
+        docker::ping(stream).map_err(Error::Ping)
+    })?;
+
+    for image in &config.required_images {
+        unix_stream::with_stream(stream_config, Error::UnixStream, |stream| {
+            docker::inspect_image(stream, image)
+                .map_err(|err| Error::MissingImage(image.clone(), err))
+        })?;
+    }
+
+    Ok(())
+}
+
+fn prepare_status_response(status: &str) -> Result<api::SuccessResponse, api::ErrorResponse> {
+    api::prepare_json_response(
+        &HealthStatus {
+            status: status.to_string(),
+        },
+        api::JsonFormat::Minimal,
+    )
+}
+
+fn handle_error(err: Error) -> api::ErrorResponse {
+    let error_code = match err {
+        Error::UnixStream(_) => "docker.unixsocket",
+        Error::Ping(_) => "docker.ping",
+        Error::MissingImage(_, _) => "docker.image.missing",
+        Error::PullingImages() => "docker.image.pulling",
+        Error::ShuttingDown() => "server.shutting_down",
+    };
+
+    log::warn!("Not ready: {}", err);
+
+    api::ErrorResponse {
+        status_code: 503,
+        body: api::ErrorBody {
+            error: error_code.to_string(),
+            message: err.to_string(),
+        },
+    }
+}
+
+pub enum Error {
+    UnixStream(unix_stream::Error),
+    Ping(docker::Error),
+    MissingImage(String, docker::Error),
+    PullingImages(),
+    ShuttingDown(),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnixStream(err) => {
+                write!(f, "Unix socket failure: {}", err)
+            }
+
+            Error::Ping(err) => {
+                write!(f, "Failed to ping docker: {}", err)
+            }
+
+            Error::MissingImage(image, err) => {
+                write!(f, "Required image {} is not available: {}", image, err)
+            }
+
+            Error::PullingImages() => {
+                write!(f, "The images to pre-pull at startup are not pulled yet")
+            }
+
+            Error::ShuttingDown() => {
+                write!(f, "The server is shutting down")
+            }
+        }
+    }
+}
+
+fn err_if_false<E>(value: bool, err: E) -> Result<(), E> {
+    if value {
+        Ok(())
+    } else {
+        Err(err)
+    }
+}
@@ -0,0 +1,183 @@
+use std::fmt;
+
+use crate::docker_run::allowlist;
+use crate::docker_run::api;
+use crate::docker_run::config;
+use crate::docker_run::docker;
+use crate::docker_run::http_extra;
+use crate::docker_run::images;
+use crate::docker_run::pool;
+use crate::docker_run::unix_stream;
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PullRequestBody {
+    pub image: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ImageInfo {
+    id: String,
+    tags: Vec<String>,
+    digests: Vec<String>,
+    size: i64,
+    created: i64,
+    // Languages in the language registry that use the image
+    languages: Vec<String>,
+    // The image matches an entry in the allowlist
+    allowlisted: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PullResult {
+    image: String,
+}
+
+pub fn handle_list(config: &config::Config) -> Result<api::SuccessResponse, api::ErrorResponse> {
+    let response = unix_stream::with_stream(&config.unix_socket, Error::UnixStream, |stream| {
+        docker::list_images(stream).map_err(Error::ListImages)
+    })
+    .map_err(handle_error)?;
+
+    let images: Vec<ImageInfo> = response
+        .body()
+        .iter()
+        .map(|image| to_image_info(config, image))
+        .collect();
+
+    api::prepare_json_response(&images, api::JsonFormat::Pretty)
+}
+
+pub fn handle_pull(
+    config: &config::Config,
+    pull_history: &images::PullHistory,
+    req_body: PullRequestBody,
+) -> Result<api::SuccessResponse, api::ErrorResponse> {
+    pull_history
+        .pull(&config.images, &config.unix_socket, &req_body.image)
+        .map_err(Error::Pull)
+        .map_err(handle_error)?;
+
+    let result = PullResult {
+        image: allowlist::normalize_image_name(&req_body.image),
+    };
+
+    api::prepare_json_response(&result, api::JsonFormat::Pretty)
+}
+
+// Pooled containers of the image are removed first, they would make docker refuse to remove it
+pub fn handle_remove(
+    config: &config::Config,
+    pool: &pool::Pool,
+    pull_history: &images::PullHistory,
+    image: &str,
+) -> Result<api::SuccessResponse, api::ErrorResponse> {
+    let discarded = pool.discard_image(image);
+
+    if discarded > 0 {
+        log::info!(
+            "Removed {} pooled containers of {} before removing the image",
+            discarded,
+            image
+        );
+    }
+
+    let response = unix_stream::with_stream(&config.unix_socket, Error::UnixStream, |stream| {
+        docker::remove_image(stream, image).map_err(Error::RemoveImage)
+    })
+    .map_err(handle_error)?;
+
+    pull_history.clear();
+
+    api::prepare_json_response(response.body(), api::JsonFormat::Pretty)
+}
+
+fn to_image_info(config: &config::Config, image: &docker::ImageSummaryResponse) -> ImageInfo {
+    let languages = config
+        .languages
+        .languages
+        .iter()
+        .filter(|(_, language_image)| {
+            let language_image = allowlist::normalize_image_name(language_image);
+            image.repo_tags.contains(&language_image)
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    let allowlisted = config.allowlist.is_enabled()
+        && image
+            .repo_tags
+            .iter()
+            .any(|tag| config.allowlist.find_entry(tag).is_some());
+
+    ImageInfo {
+        id: image.id.clone(),
+        tags: image.repo_tags.clone(),
+        digests: image.repo_digests.clone(),
+        size: image.size,
+        created: image.created,
+        languages,
+        allowlisted,
+    }
+}
+
+fn handle_error(err: Error) -> api::ErrorResponse {
+    let (status_code, error_code) = match &err {
+        Error::UnixStream(_) => (500, "docker.unixsocket"),
+        Error::ListImages(_) => (500, "docker.image.list"),
+        Error::RemoveImage(docker_error) => (status_of(docker_error), "docker.image.remove"),
+        Error::Pull(images::Error::PullFailed { .. }) => (400, "docker.image.pull"),
+        Error::Pull(_) => (500, "docker.image.pull"),
+    };
+
+    api::ErrorResponse {
+        status_code,
+        body: api::ErrorBody {
+            error: error_code.to_string(),
+            message: err.to_string(),
+        },
+    }
+}
+
+// Passes on not found and conflict (image in use) from docker
+fn status_of(err: &docker::Error) -> u16 {
+    match err {
+        docker::Error::SendRequest(http_extra::Error::BadStatus(status, _))
+            if status.as_u16() == 404 || status.as_u16() == 409 =>
+        {
+            status.as_u16()
+        }
+
+        _ => 500,
+    }
+}
+
+pub enum Error {
+    UnixStream(unix_stream::Error),
+    ListImages(docker::Error),
+    RemoveImage(docker::Error),
+    Pull(images::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnixStream(err) => {
+                write!(f, "Unix socket failure: {}", err)
+            }
+
+            Error::ListImages(err) => {
+                write!(f, "Failed to list images: {}", err)
+            }
+
+            Error::RemoveImage(err) => {
+                write!(f, "Failed to remove image: {}", err)
+            }
+
+            Error::Pull(err) => {
+                write!(f, "{}", err)
+            }
+        }
+    }
+}
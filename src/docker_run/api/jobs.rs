@@ -0,0 +1,125 @@
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use std::thread;
+
+use crate::docker_run::api;
+use crate::docker_run::config;
+use crate::docker_run::jobs;
+use crate::docker_run::rate_limit;
+use crate::docker_run::runtime;
+
+pub type JobResult = Result<Map<String, Value>, api::ErrorResponse>;
+
+pub type Store = jobs::Store<JobResult>;
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JobInfo<'a> {
+    id: &'a str,
+    status: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<&'a Map<String, Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a api::ErrorBody>,
+}
+
+pub fn handle_submit(
+    config: config::Config,
+    store: Arc<Store>,
+    runtime: Arc<runtime::Runtime>,
+    req_body: api::run::RequestBody,
+    include_timings: bool,
+    permit: rate_limit::Permit,
+) -> Result<api::SuccessResponse, api::ErrorResponse> {
+    // The job would fail right away, the client is told before the job is accepted
+    runtime
+        .queue
+        .check_capacity()
+        .map_err(api::run::handle_queue_error)?;
+
+    let id = store.insert().map_err(handle_error)?;
+
+    let job_id = id.clone();
+    let job_store = Arc::clone(&store);
+
+    thread::spawn(move || {
+        let result = api::run::execute(&config, &runtime, req_body, include_timings);
+        job_store.finish(&job_id, result);
+
+        // The concurrent run slot of the token is held until the job is finished
+        drop(permit);
+    });
+
+    let info = JobInfo {
+        id: &id,
+        status: "running",
+        result: None,
+        error: None,
+    };
+
+    api::prepare_json_response(&info, api::JsonFormat::Minimal).map(|response| {
+        api::SuccessResponse {
+            status_code: 202,
+            ..response
+        }
+    })
+}
+
+pub fn handle_get(store: &Store, id: &str) -> Result<api::SuccessResponse, api::ErrorResponse> {
+    let job = store.get(id).ok_or_else(|| not_found_error(id))?;
+
+    api::prepare_json_response(&to_job_info(&job), api::JsonFormat::Minimal)
+}
+
+pub fn handle_delete(store: &Store, id: &str) -> Result<api::SuccessResponse, api::ErrorResponse> {
+    let job = store.remove(id).ok_or_else(|| not_found_error(id))?;
+
+    api::prepare_json_response(&to_job_info(&job), api::JsonFormat::Minimal)
+}
+
+fn to_job_info(job: &jobs::Job<JobResult>) -> JobInfo<'_> {
+    match &job.status {
+        jobs::Status::Running() => JobInfo {
+            id: &job.id,
+            status: "running",
+            result: None,
+            error: None,
+        },
+
+        jobs::Status::Finished(Ok(result)) => JobInfo {
+            id: &job.id,
+            status: "completed",
+            result: Some(result),
+            error: None,
+        },
+
+        jobs::Status::Finished(Err(err)) => JobInfo {
+            id: &job.id,
+            status: "failed",
+            result: None,
+            error: Some(&err.body),
+        },
+    }
+}
+
+fn not_found_error(id: &str) -> api::ErrorResponse {
+    api::ErrorResponse {
+        status_code: 404,
+        body: api::ErrorBody {
+            error: "jobs.not_found".to_string(),
+            message: format!("Job not found: {}", id),
+        },
+    }
+}
+
+fn handle_error(err: jobs::Error) -> api::ErrorResponse {
+    match err {
+        jobs::Error::Full(_) => api::ErrorResponse {
+            status_code: 503,
+            body: api::ErrorBody {
+                error: "jobs.full".to_string(),
+                message: err.to_string(),
+            },
+        },
+    }
+}
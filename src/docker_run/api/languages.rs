@@ -0,0 +1,19 @@
+use crate::docker_run::api;
+use crate::docker_run::config;
+
+#[derive(Debug, serde::Serialize)]
+struct LanguageInfo<'a> {
+    name: &'a str,
+    image: &'a str,
+}
+
+pub fn handle(config: &config::Config) -> Result<api::SuccessResponse, api::ErrorResponse> {
+    let languages: Vec<LanguageInfo> = config
+        .languages
+        .languages
+        .iter()
+        .map(|(name, image)| LanguageInfo { name, image })
+        .collect();
+
+    api::prepare_json_response(&languages, api::JsonFormat::Pretty)
+}
@@ -1,18 +1,49 @@
+pub mod health;
+pub mod images;
+pub mod jobs;
+pub mod languages;
 pub mod root;
 pub mod run;
+pub mod run_batch;
+pub mod run_stream;
 pub mod version;
 
-#[derive(Debug, Clone)]
-pub struct ApiConfig {
-    pub access_token: String,
+use crate::docker_run::auth;
+use crate::docker_run::rate_limit;
+
+pub fn authorization_error(err: auth::Error) -> ErrorResponse {
+    let (status_code, error_code) = match err {
+        auth::Error::Missing() | auth::Error::Invalid() => (401, "access_token"),
+
+        auth::Error::Expired(_) => (401, "access_token.expired"),
+
+        auth::Error::MissingScope { .. } => (403, "access_token.scope"),
+
+        auth::Error::ReadFile(_) | auth::Error::ParseFile(_) | auth::Error::InvalidToken { .. } => {
+            (500, "access_token.file")
+        }
+    };
+
+    ErrorResponse {
+        status_code,
+        body: ErrorBody {
+            error: error_code.to_string(),
+            message: err.to_string(),
+        },
+    }
 }
 
-pub fn authorization_error() -> ErrorResponse {
+pub fn rate_limit_error(err: &rate_limit::Error) -> ErrorResponse {
+    let status_code = match err {
+        rate_limit::Error::BurstExceeded { .. } => 400,
+        _ => 429,
+    };
+
     ErrorResponse {
-        status_code: 401,
+        status_code,
         body: ErrorBody {
-            error: "access_token".to_string(),
-            message: "Missing or wrong access token".to_string(),
+            error: "limits.rate".to_string(),
+            message: err.to_string(),
         },
     }
 }
@@ -53,13 +84,13 @@ pub fn prepare_json_response<T: serde::Serialize>(
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ErrorResponse {
     pub status_code: u16,
     pub body: ErrorBody,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ErrorBody {
     pub error: String,
@@ -1,37 +1,555 @@
+use base64::Engine;
 use serde_json::{Map, Value};
+use std::time::Instant;
 
+use crate::docker_run::allowlist;
 use crate::docker_run::api;
 use crate::docker_run::config;
 use crate::docker_run::docker;
+use crate::docker_run::images;
+use crate::docker_run::limits;
+use crate::docker_run::queue;
 use crate::docker_run::run;
+use crate::docker_run::runtime;
 
 #[derive(Debug, serde::Deserialize)]
 pub struct RequestBody {
+    #[serde(default)]
+    pub image: Option<String>,
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(default)]
+    pub mode: Mode,
+    #[serde(default)]
+    pub payload: Option<Map<String, Value>>,
+    #[serde(default)]
+    pub cmd: Option<Vec<String>>,
+    #[serde(default)]
+    pub stdin: Option<String>,
+    // Stdin that is not valid utf-8, only one of stdin and stdinBase64 can be set
+    #[serde(default, rename = "stdinBase64")]
+    pub stdin_base64: Option<String>,
+    #[serde(default)]
+    pub limits: Option<limits::Overrides>,
+}
+
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    // The image speaks the glot protocol: json payload on stdin and json result on stdout
+    #[default]
+    Json,
+    // Any image, stdin is passed as-is and stdout, stderr and the exit code is returned
+    Raw,
+}
+
+pub struct PreparedRun {
+    // The requested image, the container config references pinned images by digest
     pub image: String,
-    pub payload: Map<String, Value>,
+    pub run_request: run::RunRequest<Map<String, Value>>,
+    pub effective_limits: Option<limits::EffectiveLimits>,
+    // Pooled containers are only used if they were created with the same container config
+    pub run_container_config: run::ContainerConfig,
+}
+
+struct PreparedContainer {
+    image: String,
+    container_config: docker::ContainerConfig,
+    limits: run::Limits,
+    effective_limits: Option<limits::EffectiveLimits>,
+    run_container_config: run::ContainerConfig,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RawResult {
+    stdout: String,
+    stderr: String,
+    exit_code: i64,
 }
 
 pub fn handle(
     config: &config::Config,
+    runtime: &runtime::Runtime,
     req_body: RequestBody,
+    include_timings: bool,
 ) -> Result<api::SuccessResponse, api::ErrorResponse> {
-    let container_config = run::prepare_container_config(req_body.image, config.container.clone());
+    let run_result = execute(config, runtime, req_body, include_timings)?;
+
+    api::prepare_json_response(&run_result, api::JsonFormat::Minimal)
+}
+
+pub fn execute(
+    config: &config::Config,
+    runtime: &runtime::Runtime,
+    req_body: RequestBody,
+    include_timings: bool,
+) -> Result<Map<String, Value>, api::ErrorResponse> {
+    let result = match req_body.mode {
+        Mode::Json => execute_json(config, runtime, req_body, include_timings),
+
+        Mode::Raw => execute_raw(config, runtime, req_body, include_timings),
+    };
+
+    if let Err(err) = &result {
+        runtime.metrics.record_error(&err.body.error);
+    }
+
+    result
+}
+
+fn execute_json(
+    config: &config::Config,
+    runtime: &runtime::Runtime,
+    req_body: RequestBody,
+    include_timings: bool,
+) -> Result<Map<String, Value>, api::ErrorResponse> {
+    let prepared = prepare(config, &runtime.pull_history, req_body)?;
+
+    let image = prepared.image;
+    let run_request = prepared.run_request;
+    let run_container_config = prepared.run_container_config;
+
+    let run_result = run_in_slot(config, runtime, &image, || {
+        run_json(
+            config,
+            runtime,
+            &image,
+            run_request,
+            &run_container_config,
+            |_, _| {},
+        )
+    })?;
+
+    Ok(build_result(
+        run_result.output,
+        &run_result.container_state,
+        include_timings.then_some(&run_result.timings),
+        prepared.effective_limits,
+    ))
+}
+
+fn execute_raw(
+    config: &config::Config,
+    runtime: &runtime::Runtime,
+    req_body: RequestBody,
+    include_timings: bool,
+) -> Result<Map<String, Value>, api::ErrorResponse> {
+    let stdin = raw_stdin(&req_body)?;
+    let prepared = prepare_container(config, &runtime.pull_history, &req_body)?;
+
+    let image = prepared.image;
+    let container_config = prepared.container_config;
+    let limits = prepared.limits;
+
+    let run_result = run_in_slot(config, runtime, &image, || {
+        run::run_raw(
+            config.unix_socket.clone(),
+            run::RawRunRequest {
+                container_config: docker::ContainerConfig {
+                    cmd: req_body.cmd,
+                    ..container_config
+                },
+                stdin,
+                limits,
+            },
+            config.debug.clone(),
+            &runtime.tracker,
+        )
+    })?;
+
+    let raw_result = RawResult {
+        stdout: String::from_utf8_lossy(&run_result.output.stdout).to_string(),
+        stderr: String::from_utf8_lossy(&run_result.output.stderr).to_string(),
+        exit_code: run_result.container_state.exit_code,
+    };
+
+    let output = match serde_json::to_value(raw_result) {
+        Ok(Value::Object(map)) => map,
+
+        Ok(value) => {
+            return Err(serialize_error(format!(
+                "Expected an object, got: {}",
+                value
+            )))
+        }
+
+        Err(err) => return Err(serialize_error(err.to_string())),
+    };
+
+    Ok(build_result(
+        output,
+        &run_result.container_state,
+        include_timings.then_some(&run_result.timings),
+        prepared.effective_limits,
+    ))
+}
+
+// Runs f while holding a container slot from the queue and records the run in the metrics
+pub fn run_in_slot<T, F>(
+    config: &config::Config,
+    runtime: &runtime::Runtime,
+    image: &str,
+    f: F,
+) -> Result<run::RunResult<T>, api::ErrorResponse>
+where
+    F: FnOnce() -> Result<run::RunResult<T>, run::Error>,
+{
+    let _ticket = runtime.queue.enter().map_err(handle_queue_error)?;
+
+    runtime
+        .metrics
+        .record_run(metrics_image_label(config, image));
 
-    let run_result = run::run(
+    let run_start = Instant::now();
+    let run_result = f();
+    runtime.metrics.record_run_duration(run_start.elapsed());
+
+    if let Ok(run_result) = &run_result {
+        runtime.metrics.record_timings(&run_result.timings);
+    }
+
+    run_result.map_err(handle_error)
+}
+
+// Clients can send any image, only allowlisted and configured images get their own label
+// so that the number of time series stays bounded
+fn metrics_image_label<'a>(config: &config::Config, image: &'a str) -> &'a str {
+    let is_allowlisted =
+        config.allowlist.is_enabled() && config.allowlist.find_entry(image).is_some();
+
+    let is_configured = || {
+        let image = allowlist::normalize_image_name(image);
+
+        config
+            .configured_images()
+            .iter()
+            .any(|configured| allowlist::normalize_image_name(configured) == image)
+    };
+
+    if is_allowlisted || is_configured() {
+        image
+    } else {
+        "other"
+    }
+}
+
+// Runs in a pooled container if the image is pooled and a container is available
+pub fn run_json<F>(
+    config: &config::Config,
+    runtime: &runtime::Runtime,
+    image: &str,
+    run_request: run::RunRequest<Map<String, Value>>,
+    run_container_config: &run::ContainerConfig,
+    mut on_output: F,
+) -> Result<run::RunResult<Map<String, Value>>, run::Error>
+where
+    F: FnMut(&docker::StreamType, &[u8]),
+{
+    // Pooled containers are created from the tag, the pinned image could be another one
+    let pins_digest = run_request.container_config.image.contains('@');
+
+    let pooled_container_id = if !pins_digest && runtime.pool.is_pooled(image, run_container_config)
+    {
+        let container_id =
+            runtime
+                .pool
+                .claim(image, run_container_config, runtime.pull_history.pulled());
+        runtime
+            .metrics
+            .record_pool_claim(image, container_id.is_some());
+        container_id
+    } else {
+        None
+    };
+
+    if let Some(container_id) = pooled_container_id {
+        let result = run::run_in_created_container(
+            config.unix_socket.clone(),
+            &run_request,
+            config.debug.clone(),
+            &runtime.tracker,
+            container_id,
+            &mut on_output,
+        );
+
+        match result {
+            // The container was removed outside of the pool, i.e. by the reaper or docker prune.
+            // Nothing has been written to the container yet, so the run can be retried.
+            Err(run::Error::StartContainer(err)) => {
+                log::warn!(
+                    "Failed to start pooled container of {}, using a new container: {}",
+                    image,
+                    err
+                );
+            }
+
+            result => return result,
+        }
+    }
+
+    run::run_with_output_handler(
         config.unix_socket.clone(),
-        run::RunRequest {
-            container_config,
-            payload: req_body.payload,
-            limits: config.run.clone(),
-        },
+        run_request,
         config.debug.clone(),
+        &runtime.tracker,
+        on_output,
     )
-    .map_err(handle_error)?;
+}
 
-    api::prepare_json_response(&run_result, api::JsonFormat::Minimal)
+// Prepares a run of an image speaking the glot protocol
+pub fn prepare(
+    config: &config::Config,
+    pull_history: &images::PullHistory,
+    req_body: RequestBody,
+) -> Result<PreparedRun, api::ErrorResponse> {
+    if let Mode::Raw = req_body.mode {
+        return Err(request_error(
+            "request.mode",
+            "Raw mode is not supported by this endpoint",
+        ));
+    }
+
+    let prepared = prepare_container(config, pull_history, &req_body)?;
+
+    let payload = req_body
+        .payload
+        .ok_or_else(|| request_error("request.payload", "Missing payload"))?;
+
+    Ok(PreparedRun {
+        image: prepared.image,
+        run_request: run::RunRequest {
+            container_config: prepared.container_config,
+            payload,
+            limits: prepared.limits,
+        },
+        effective_limits: prepared.effective_limits,
+        run_container_config: prepared.run_container_config,
+    })
+}
+
+fn prepare_container(
+    config: &config::Config,
+    pull_history: &images::PullHistory,
+    req_body: &RequestBody,
+) -> Result<PreparedContainer, api::ErrorResponse> {
+    let image = resolve_image(config, &req_body.image, &req_body.language)?;
+
+    let reference = allowlist::check(&config.allowlist, &image).map_err(handle_allowlist_error)?;
+
+    // The digest can only be verified once the image is pulled
+    images::ensure_image(
+        &config.images,
+        pull_history,
+        &config.unix_socket,
+        &reference,
+    )
+    .map_err(handle_images_error)?;
+
+    allowlist::verify_digest(&config.allowlist, &config.unix_socket, &image)
+        .map_err(handle_allowlist_error)?;
+
+    let mut limits = config.run.clone();
+    let mut container_config = config.container.clone();
+
+    if let Some(profile) = config.profiles.find(&image, req_body.language.as_deref()) {
+        log::debug!("Using profile {} for image {}", profile.name, image);
+        profile.apply(&mut limits, &mut container_config);
+    }
+
+    let effective_limits = match &req_body.limits {
+        Some(overrides) => {
+            limits::apply(
+                &config.limits,
+                overrides,
+                &mut limits,
+                &mut container_config,
+            )
+            .map_err(handle_limits_error)?;
+
+            Some(limits::EffectiveLimits::new(&limits, &container_config))
+        }
+
+        None => None,
+    };
+
+    // Pinned images are created by digest, so a tag that is moved after the check doesn't change the image
+    let run_container_config = container_config.clone();
+    let container_config = run::prepare_container_config(reference, container_config);
+
+    Ok(PreparedContainer {
+        image,
+        container_config,
+        limits,
+        effective_limits,
+        run_container_config,
+    })
+}
+
+// Clients either send the image directly or a language that is mapped to an image
+pub fn resolve_image(
+    config: &config::Config,
+    image: &Option<String>,
+    language: &Option<String>,
+) -> Result<String, api::ErrorResponse> {
+    match (image, language) {
+        (Some(image), None) => Ok(image.clone()),
+
+        (None, Some(language)) => config
+            .languages
+            .image_for(language)
+            .cloned()
+            .ok_or_else(|| api::ErrorResponse {
+                status_code: 400,
+                body: api::ErrorBody {
+                    error: "language.unknown".to_string(),
+                    message: format!("Unknown language: {}", language),
+                },
+            }),
+
+        (Some(_), Some(_)) => Err(request_error(
+            "request.image",
+            "Only one of image and language can be given",
+        )),
+
+        (None, None) => Err(request_error("request.image", "Missing image or language")),
+    }
+}
+
+fn raw_stdin(req_body: &RequestBody) -> Result<Vec<u8>, api::ErrorResponse> {
+    match (&req_body.stdin, &req_body.stdin_base64) {
+        (Some(_), Some(_)) => Err(request_error(
+            "request.stdin",
+            "Only one of stdin and stdinBase64 can be set",
+        )),
+
+        (Some(stdin), None) => Ok(stdin.clone().into_bytes()),
+
+        (None, Some(stdin_base64)) => base64::engine::general_purpose::STANDARD
+            .decode(stdin_base64)
+            .map_err(|err| {
+                request_error(
+                    "request.stdin",
+                    &format!("Failed to decode stdinBase64: {}", err),
+                )
+            }),
+
+        (None, None) => Ok(Vec::new()),
+    }
+}
+
+fn serialize_error(details: String) -> api::ErrorResponse {
+    api::ErrorResponse {
+        status_code: 500,
+        body: api::ErrorBody {
+            error: "response.serialize".to_string(),
+            message: format!("Failed to serialize response: {}", details),
+        },
+    }
+}
+
+fn request_error(error_code: &str, message: &str) -> api::ErrorResponse {
+    api::ErrorResponse {
+        status_code: 400,
+        body: api::ErrorBody {
+            error: error_code.to_string(),
+            message: message.to_string(),
+        },
+    }
+}
+
+// Adds the container state to the output and optionally the timings and effective limits
+pub fn build_result(
+    mut output: Map<String, Value>,
+    container_state: &run::ContainerState,
+    timings: Option<&run::Timings>,
+    effective_limits: Option<limits::EffectiveLimits>,
+) -> Map<String, Value> {
+    // Nested under its own key, the runner output is rejected if it contains the key
+    let mut container = match serde_json::to_value(container_state) {
+        Ok(Value::Object(state)) => state,
+        _ => Map::new(),
+    };
+
+    if let Some(value) = timings.and_then(|timings| serde_json::to_value(timings).ok()) {
+        container.insert("timings".to_string(), value);
+    }
+
+    // Echo the effective limits back to the client when the request overrides any limits
+    if let Some(value) = effective_limits.and_then(|limits| serde_json::to_value(limits).ok()) {
+        container.insert("limits".to_string(), value);
+    }
+
+    output.insert(run::CONTAINER_KEY.to_string(), Value::Object(container));
+
+    output
+}
+
+fn handle_allowlist_error(err: allowlist::Error) -> api::ErrorResponse {
+    let (status_code, error_code) = match err {
+        allowlist::Error::NotAllowed(_) => (403, "image.not_allowed"),
+
+        allowlist::Error::DigestMismatch { .. } => (403, "image.not_allowed"),
+
+        allowlist::Error::UnixStream(_) => (500, "docker.unixsocket"),
+
+        allowlist::Error::InspectImage(_) => (500, "docker.image.inspect"),
+    };
+
+    api::ErrorResponse {
+        status_code,
+        body: api::ErrorBody {
+            error: error_code.to_string(),
+            message: err.to_string(),
+        },
+    }
+}
+
+fn handle_images_error(err: images::Error) -> api::ErrorResponse {
+    let (status_code, error_code) = match err {
+        images::Error::UnixStream(_) => (500, "docker.unixsocket"),
+        images::Error::InspectImage(_) => (500, "docker.image.inspect"),
+        images::Error::PullImage(_) => (500, "docker.image.pull"),
+        images::Error::PullFailed { .. } => (400, "docker.image.pull"),
+    };
+
+    api::ErrorResponse {
+        status_code,
+        body: api::ErrorBody {
+            error: error_code.to_string(),
+            message: err.to_string(),
+        },
+    }
 }
 
-fn handle_error(err: run::Error) -> api::ErrorResponse {
+fn handle_limits_error(err: limits::Error) -> api::ErrorResponse {
+    match err {
+        limits::Error::Exceeded { .. } | limits::Error::Below { .. } => api::ErrorResponse {
+            status_code: 400,
+            body: api::ErrorBody {
+                error: "limits.override".to_string(),
+                message: err.to_string(),
+            },
+        },
+    }
+}
+
+pub fn handle_queue_error(err: queue::Error) -> api::ErrorResponse {
+    let error_code = match err {
+        queue::Error::Full(_) | queue::Error::MaxWait(_) => "server.overloaded",
+        queue::Error::Closed() => "server.shutting_down",
+    };
+
+    api::ErrorResponse {
+        status_code: 503,
+        body: api::ErrorBody {
+            error: error_code.to_string(),
+            message: err.to_string(),
+        },
+    }
+}
+
+pub fn handle_error(err: run::Error) -> api::ErrorResponse {
     match &err {
         run::Error::UnixStream(_) => error_response(&err, 500, "docker.unixsocket"),
 
@@ -41,6 +559,14 @@ fn handle_error(err: run::Error) -> api::ErrorResponse {
 
         run::Error::AttachContainer(_) => error_response(&err, 500, "docker.container.attach"),
 
+        run::Error::WaitContainer(_) => error_response(&err, 500, "docker.container.wait"),
+
+        run::Error::InspectContainer(_) => error_response(&err, 500, "docker.container.inspect"),
+
+        run::Error::OutOfMemory(_) => error_response(&err, 400, "limits.memory"),
+
+        run::Error::WriteStdin(_) => error_response(&err, 500, "docker.container.stream.stdin"),
+
         run::Error::SerializePayload(_) => {
             error_response(&err, 400, "docker.container.stream.payload.serialize")
         }
@@ -60,6 +586,12 @@ fn handle_error(err: run::Error) -> api::ErrorResponse {
         run::Error::StreamStderr(_) => error_response(&err, 500, "coderunner.stderr"),
 
         run::Error::StreamStdoutDecode(_) => error_response(&err, 500, "coderunner.stdout.decode"),
+
+        run::Error::StreamStdoutReservedKey(_) => {
+            error_response(&err, 500, "coderunner.stdout.reserved_key")
+        }
+
+        run::Error::ShuttingDown() => error_response(&err, 503, "server.shutting_down"),
     }
 }
 
@@ -0,0 +1,152 @@
+use serde_json::{Map, Value};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::docker_run::api;
+use crate::docker_run::config;
+use crate::docker_run::limits;
+use crate::docker_run::runtime;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub max_parallelism: usize,
+    pub max_size: usize,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct RequestBody {
+    #[serde(default)]
+    pub image: Option<String>,
+    #[serde(default)]
+    pub language: Option<String>,
+    pub payloads: Vec<Map<String, Value>>,
+    #[serde(default)]
+    pub limits: Option<limits::Overrides>,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(untagged)]
+enum BatchItem {
+    Success(Map<String, Value>),
+    Error(api::ErrorBody),
+}
+
+// Max parallelism is the number of concurrent runs the token was granted for the batch
+pub fn handle(
+    config: &config::Config,
+    runtime: &runtime::Runtime,
+    req_body: RequestBody,
+    max_parallelism: usize,
+) -> Result<api::SuccessResponse, api::ErrorResponse> {
+    let max_size = config.batch.max_size;
+
+    if req_body.payloads.len() > max_size {
+        return Err(api::ErrorResponse {
+            status_code: 400,
+            body: api::ErrorBody {
+                error: "batch.size".to_string(),
+                message: format!(
+                    "Too many payloads in batch: {} (max: {})",
+                    req_body.payloads.len(),
+                    max_size
+                ),
+            },
+        });
+    }
+
+    // Fail early on an unknown language instead of once per payload
+    api::run::resolve_image(config, &req_body.image, &req_body.language)?;
+
+    let items = run_batch(config, runtime, req_body, max_parallelism);
+
+    api::prepare_json_response(&items, api::JsonFormat::Minimal)
+}
+
+fn run_batch(
+    config: &config::Config,
+    runtime: &runtime::Runtime,
+    req_body: RequestBody,
+    max_parallelism: usize,
+) -> Vec<BatchItem> {
+    let image = req_body.image;
+    let language = req_body.language;
+    let limits = req_body.limits;
+    let payloads = req_body.payloads;
+    let next_index = AtomicUsize::new(0);
+
+    // Every payload takes a queue slot, a batch doesn't fill the queue with its own payloads
+    let worker_count = config
+        .batch
+        .max_parallelism
+        .min(max_parallelism)
+        .min(runtime.queue.free_slots())
+        .clamp(1, payloads.len().max(1));
+
+    let mut results: Vec<(usize, BatchItem)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..worker_count)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+
+                    // Each worker claims the next unprocessed payload until all are taken
+                    loop {
+                        let index = next_index.fetch_add(1, Ordering::SeqCst);
+
+                        let payload = match payloads.get(index) {
+                            Some(payload) => payload.clone(),
+                            None => break,
+                        };
+
+                        let run_body = api::run::RequestBody {
+                            image: image.clone(),
+                            language: language.clone(),
+                            mode: api::run::Mode::Json,
+                            payload: Some(payload),
+                            cmd: None,
+                            stdin: None,
+                            stdin_base64: None,
+                            limits: limits.clone(),
+                        };
+
+                        let item = match api::run::execute(config, runtime, run_body, false) {
+                            Ok(result) => BatchItem::Success(result),
+                            Err(err) => BatchItem::Error(err.body),
+                        };
+
+                        results.push((index, item));
+                    }
+
+                    results
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| {
+                worker.join().unwrap_or_else(|_| {
+                    log::error!("Batch worker panicked, its results are lost");
+                    Vec::new()
+                })
+            })
+            .collect()
+    });
+
+    results.sort_by_key(|(index, _)| *index);
+
+    // The payloads of a panicked worker have no result, every payload gets an item
+    let mut results = results.into_iter().peekable();
+
+    (0..payloads.len())
+        .map(
+            |index| match results.next_if(|(result_index, _)| *result_index == index) {
+                Some((_, item)) => item,
+                None => BatchItem::Error(api::ErrorBody {
+                    error: "batch.worker".to_string(),
+                    message: "The payload was not run to completion, the batch worker failed"
+                        .to_string(),
+                }),
+            },
+        )
+        .collect()
+}
@@ -0,0 +1,195 @@
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::mem;
+use std::str;
+
+use crate::docker_run::api;
+use crate::docker_run::config;
+use crate::docker_run::runtime;
+
+pub struct Event {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+impl Event {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!("event: {}\ndata: ", self.name).into_bytes();
+        bytes.extend_from_slice(&self.data);
+        bytes.extend_from_slice(b"\n\n");
+        bytes
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+struct OutputData {
+    data: String,
+}
+
+// Output frames can end in the middle of a character, the incomplete bytes
+// are decoded together with the next frame of the same stream
+#[derive(Default)]
+struct Utf8Decoder {
+    incomplete: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    fn decode(&mut self, data: &[u8]) -> String {
+        let mut bytes = mem::take(&mut self.incomplete);
+        bytes.extend_from_slice(data);
+
+        let mut output = String::new();
+        let mut rest = &bytes[..];
+
+        loop {
+            match str::from_utf8(rest) {
+                Ok(valid) => {
+                    output.push_str(valid);
+                    return output;
+                }
+
+                Err(err) => {
+                    let (valid, invalid) = rest.split_at(err.valid_up_to());
+                    output.push_str(&String::from_utf8_lossy(valid));
+
+                    match err.error_len() {
+                        Some(len) => {
+                            output.push(char::REPLACEMENT_CHARACTER);
+                            rest = &invalid[len..];
+                        }
+
+                        None => {
+                            self.incomplete = invalid.to_vec();
+                            return output;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Bytes left when the stream ended can't be completed anymore
+    fn finish(&mut self) -> Option<String> {
+        let incomplete = mem::take(&mut self.incomplete);
+
+        (!incomplete.is_empty()).then(|| String::from_utf8_lossy(&incomplete).to_string())
+    }
+}
+
+pub fn handle<F>(
+    config: &config::Config,
+    runtime: &runtime::Runtime,
+    req_body: api::run::RequestBody,
+    include_timings: bool,
+    mut send: F,
+) where
+    F: FnMut(Event),
+{
+    let prepared = match api::run::prepare(config, &runtime.pull_history, req_body) {
+        Ok(prepared) => prepared,
+
+        Err(err) => {
+            runtime.metrics.record_error(&err.body.error);
+            return send(error_event(&err.body));
+        }
+    };
+
+    let image = prepared.image;
+    let run_request = prepared.run_request;
+    let run_container_config = prepared.run_container_config;
+    let mut decoders: BTreeMap<&'static str, Utf8Decoder> = BTreeMap::new();
+
+    let run_result = api::run::run_in_slot(config, runtime, &image, || {
+        api::run::run_json(
+            config,
+            runtime,
+            &image,
+            run_request,
+            &run_container_config,
+            |stream_type, data| {
+                let decoder = decoders.entry(stream_type.name()).or_default();
+                send(output_event(stream_type.name(), decoder.decode(data)))
+            },
+        )
+    });
+
+    for (name, decoder) in decoders.iter_mut() {
+        if let Some(data) = decoder.finish() {
+            send(output_event(name, data));
+        }
+    }
+
+    let event = match run_result {
+        Ok(run_result) => result_event(&api::run::build_result(
+            run_result.output,
+            &run_result.container_state,
+            include_timings.then_some(&run_result.timings),
+            prepared.effective_limits,
+        )),
+
+        Err(err) => {
+            runtime.metrics.record_error(&err.body.error);
+            error_event(&err.body)
+        }
+    };
+
+    send(event)
+}
+
+fn output_event(name: &str, data: String) -> Event {
+    json_event(name, &OutputData { data })
+}
+
+fn result_event(result: &Map<String, Value>) -> Event {
+    json_event("result", result)
+}
+
+fn error_event(body: &api::ErrorBody) -> Event {
+    json_event("error", body)
+}
+
+fn json_event<T: serde::Serialize>(name: &str, data: &T) -> Event {
+    match serde_json::to_vec(data) {
+        Ok(data) => Event {
+            name: name.to_string(),
+            data,
+        },
+
+        Err(err) => {
+            let body = api::ErrorBody {
+                error: "response.serialize".to_string(),
+                message: format!("Failed to serialize event: {}", err),
+            };
+
+            Event {
+                name: "error".to_string(),
+                data: serde_json::to_vec(&body).unwrap_or_default(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_character_split_between_frames() {
+        let mut decoder = Utf8Decoder::default();
+        let bytes = "aé€b".as_bytes();
+
+        assert_eq!(decoder.decode(&bytes[..2]), "a");
+        assert_eq!(decoder.decode(&bytes[2..4]), "é");
+        assert_eq!(decoder.decode(&bytes[4..]), "€b");
+        assert_eq!(decoder.finish(), None);
+    }
+
+    #[test]
+    fn decode_invalid_bytes() {
+        let mut decoder = Utf8Decoder::default();
+
+        assert_eq!(decoder.decode(b"a\xffb"), "a\u{fffd}b");
+        assert_eq!(decoder.decode(b"c\xe2\x82"), "c");
+        assert_eq!(decoder.finish(), Some("\u{fffd}".to_string()));
+    }
+}
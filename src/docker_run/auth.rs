@@ -0,0 +1,281 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::SystemTime;
+
+use crate::docker_run::rate_limit;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    // Legacy single token, it gets the name «default» and all scopes
+    pub access_token: Option<String>,
+    pub tokens_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Run,
+    Version,
+    Metrics,
+    Admin,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scope::Run => write!(f, "run"),
+            Scope::Version => write!(f, "version"),
+            Scope::Metrics => write!(f, "metrics"),
+            Scope::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Token {
+    pub name: String,
+    pub token: String,
+    pub scopes: Vec<Scope>,
+    #[serde(default, deserialize_with = "deserialize_expires_at")]
+    pub expires_at: Option<SystemTime>,
+    #[serde(default)]
+    pub rate_limit: Option<rate_limit::RateLimit>,
+}
+
+impl Token {
+    // The admin scope grants access to everything
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .map(|expires_at| expires_at <= SystemTime::now())
+            .unwrap_or(false)
+    }
+}
+
+fn deserialize_expires_at<'de, D>(deserializer: D) -> Result<Option<SystemTime>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+
+    value
+        .map(|s| humantime::parse_rfc3339_weak(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+struct TokenFile {
+    tokens: Vec<Token>,
+    modified: Option<SystemTime>,
+}
+
+pub struct Store {
+    config: Config,
+    file: RwLock<TokenFile>,
+}
+
+impl Store {
+    pub fn new(config: Config) -> Result<Store, Error> {
+        let file = match &config.tokens_file {
+            Some(path) => read_token_file(path)?,
+
+            None => TokenFile {
+                tokens: vec![],
+                modified: None,
+            },
+        };
+
+        Ok(Store {
+            config,
+            file: RwLock::new(file),
+        })
+    }
+
+    pub fn authorize(&self, access_token: Option<&str>, scope: Scope) -> Result<Token, Error> {
+        self.reload_if_modified();
+
+        let access_token = access_token.ok_or(Error::Missing())?;
+        let token = self.find_token(access_token).ok_or(Error::Invalid())?;
+
+        err_if_false(!token.is_expired(), Error::Expired(token.name.clone()))?;
+
+        err_if_false(
+            token.has_scope(scope),
+            Error::MissingScope {
+                name: token.name.clone(),
+                scope,
+            },
+        )?;
+
+        Ok(token)
+    }
+
+    // Re-reads the token file if it has been changed since it was last read.
+    // The current tokens are kept if the file can't be read.
+    pub fn reload_if_modified(&self) {
+        let path = match &self.config.tokens_file {
+            Some(path) => path,
+            None => return,
+        };
+
+        let modified = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+
+        if modified.is_none() || modified == self.read_file().modified {
+            return;
+        }
+
+        match read_token_file(path) {
+            Ok(file) => {
+                log::info!(
+                    "Reloaded access tokens from {} ({} tokens)",
+                    path.display(),
+                    file.tokens.len()
+                );
+
+                *self.write_file() = file;
+            }
+
+            Err(err) => {
+                log::error!("Failed to reload access tokens: {}", err);
+            }
+        }
+    }
+
+    fn find_token(&self, access_token: &str) -> Option<Token> {
+        let default_token = self.config.access_token.as_ref().map(|token| Token {
+            name: "default".to_string(),
+            token: token.clone(),
+            scopes: vec![Scope::Admin],
+            expires_at: None,
+            rate_limit: None,
+        });
+
+        let file = self.read_file();
+
+        // All tokens are compared to avoid leaking which token matched through timing
+        file.tokens
+            .iter()
+            .chain(default_token.iter())
+            .fold(None, |found, token| {
+                let is_match = constant_time_eq(token.token.as_bytes(), access_token.as_bytes());
+
+                if is_match && found.is_none() {
+                    Some(token.clone())
+                } else {
+                    found
+                }
+            })
+    }
+
+    fn read_file(&self) -> RwLockReadGuard<'_, TokenFile> {
+        self.file.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn write_file(&self) -> RwLockWriteGuard<'_, TokenFile> {
+        self.file.write().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+fn read_token_file(path: &PathBuf) -> Result<TokenFile, Error> {
+    let modified = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok();
+
+    let data = fs::read(path).map_err(Error::ReadFile)?;
+    let tokens: Vec<Token> = serde_json::from_slice(&data).map_err(Error::ParseFile)?;
+
+    for token in &tokens {
+        // An empty token would match a request with an empty header
+        err_if_false(
+            !token.token.is_empty(),
+            Error::InvalidToken {
+                name: token.name.clone(),
+                details: "token must not be empty".to_string(),
+            },
+        )?;
+
+        if let Some(rate_limit) = &token.rate_limit {
+            rate_limit
+                .validate()
+                .map_err(|details| Error::InvalidToken {
+                    name: token.name.clone(),
+                    details,
+                })?;
+        }
+    }
+
+    Ok(TokenFile { tokens, modified })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn err_if_false<E>(value: bool, err: E) -> Result<(), E> {
+    if value {
+        Ok(())
+    } else {
+        Err(err)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Missing(),
+    Invalid(),
+    Expired(String),
+    MissingScope { name: String, scope: Scope },
+    ReadFile(io::Error),
+    ParseFile(serde_json::Error),
+    InvalidToken { name: String, details: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Missing() => {
+                write!(f, "Missing access token")
+            }
+
+            Error::Invalid() => {
+                write!(f, "Wrong access token")
+            }
+
+            Error::Expired(name) => {
+                write!(f, "Access token has expired: {}", name)
+            }
+
+            Error::MissingScope { name, scope } => {
+                write!(f, "Access token {} is missing the scope: {}", name, scope)
+            }
+
+            Error::ReadFile(err) => {
+                write!(f, "Failed to read access tokens file: {}", err)
+            }
+
+            Error::ParseFile(err) => {
+                write!(f, "Failed to parse access tokens file: {}", err)
+            }
+
+            Error::InvalidToken { name, details } => {
+                write!(
+                    f,
+                    "Invalid access token {} in tokens file: {}",
+                    name, details
+                )
+            }
+        }
+    }
+}
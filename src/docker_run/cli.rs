@@ -0,0 +1,131 @@
+use std::fmt;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "Usage: docker-run [command] [--config <path>]
+
+Commands:
+    serve           Start the http server (default)
+    check-config    Validate the configuration and report all errors
+    doctor          Check the connection to docker and the configured images
+    exec            Run a payload once without the http server:
+                    exec (--image <image> | --language <language>) --payload <path or ->";
+
+#[derive(Debug)]
+pub struct Args {
+    pub command: Command,
+    pub config_path: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Serve,
+    CheckConfig,
+    Doctor,
+    Exec(ExecArgs),
+    Help,
+}
+
+#[derive(Debug)]
+pub struct ExecArgs {
+    pub image: Option<String>,
+    pub language: Option<String>,
+    // A json file with the payload, - reads the payload from stdin
+    pub payload_path: PathBuf,
+}
+
+pub fn parse(args: Vec<String>) -> Result<Args, Error> {
+    let mut command_name = None;
+    let mut help = false;
+    let mut config_path = None;
+    let mut image = None;
+    let mut language = None;
+    let mut payload_path = None;
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        // Flags take their value from the next argument or after =, i.e. --config=docker-run.toml
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value)),
+            _ => (arg.clone(), None),
+        };
+
+        let mut flag_value = |name: &'static str| {
+            inline_value
+                .map(|value| value.to_string())
+                .or_else(|| args.next())
+                .ok_or(Error::MissingValue(name))
+        };
+
+        match flag.as_str() {
+            "--config" => config_path = Some(PathBuf::from(flag_value("--config")?)),
+            "--image" => image = Some(flag_value("--image")?),
+            "--language" => language = Some(flag_value("--language")?),
+            "--payload" => payload_path = Some(PathBuf::from(flag_value("--payload")?)),
+            "--help" | "-h" => help = true,
+            _ if command_name.is_none() && !arg.starts_with('-') => command_name = Some(arg),
+            _ => return Err(Error::UnexpectedArgument(arg)),
+        }
+    }
+
+    let command = match command_name.as_deref().unwrap_or("serve") {
+        _ if help => Command::Help,
+        "serve" => Command::Serve,
+        "check-config" => Command::CheckConfig,
+        "doctor" => Command::Doctor,
+        "help" => Command::Help,
+
+        "exec" => Command::Exec(ExecArgs {
+            image: image.take(),
+            language: language.take(),
+            payload_path: payload_path
+                .take()
+                .ok_or(Error::MissingValue("--payload"))?,
+        }),
+
+        name => return Err(Error::UnknownCommand(name.to_string())),
+    };
+
+    // The run flags are only used by exec
+    let unused_flag = [
+        ("--image", image.is_some()),
+        ("--language", language.is_some()),
+        ("--payload", payload_path.is_some()),
+    ]
+    .iter()
+    .find(|(_, is_set)| *is_set)
+    .map(|(flag, _)| flag.to_string());
+
+    if let Some(flag) = unused_flag {
+        return Err(Error::UnexpectedArgument(flag));
+    }
+
+    Ok(Args {
+        command,
+        config_path,
+    })
+}
+
+#[derive(Debug)]
+pub enum Error {
+    UnknownCommand(String),
+    UnexpectedArgument(String),
+    MissingValue(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnknownCommand(name) => {
+                write!(f, "Unknown command: {}", name)
+            }
+
+            Error::UnexpectedArgument(arg) => {
+                write!(f, "Unexpected argument: {}", arg)
+            }
+
+            Error::MissingValue(flag) => {
+                write!(f, "Missing value for {}", flag)
+            }
+        }
+    }
+}
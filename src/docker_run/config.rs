@@ -1,21 +1,137 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::docker_run::allowlist;
 use crate::docker_run::api;
+use crate::docker_run::auth;
 use crate::docker_run::debug;
+use crate::docker_run::images;
+use crate::docker_run::jobs;
+use crate::docker_run::languages;
+use crate::docker_run::limits;
+use crate::docker_run::pool;
+use crate::docker_run::profiles;
+use crate::docker_run::queue;
+use crate::docker_run::rate_limit;
+use crate::docker_run::reaper;
 use crate::docker_run::run;
 use crate::docker_run::unix_stream;
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub server: ServerConfig,
-    pub api: api::ApiConfig,
+    pub auth: auth::Config,
+    pub rate_limit: rate_limit::Config,
     pub unix_socket: unix_stream::Config,
     pub container: run::ContainerConfig,
+    pub profiles: profiles::Config,
+    pub allowlist: allowlist::Config,
+    pub images: images::Config,
+    pub languages: languages::Config,
     pub run: run::Limits,
+    pub queue: queue::Config,
+    pub pool: pool::Config,
+    pub limits: limits::Config,
     pub debug: debug::Config,
+    pub reaper: reaper::Config,
+    pub health: api::health::Config,
+    pub metrics: MetricsConfig,
+    pub jobs: jobs::Config,
+    pub batch: api::run_batch::Config,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ServerConfig {
     pub listen_addr: String,
     pub listen_port: u16,
     pub worker_threads: usize,
+    pub shutdown_grace_period: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricsConfig {
+    pub listen_addr: String,
+    pub listen_port: Option<u16>,
+}
+
+impl Config {
+    // Images from the languages file, pre-pull, readiness and pool settings
+    pub fn configured_images(&self) -> BTreeSet<String> {
+        self.languages
+            .languages
+            .values()
+            .chain(&self.images.pre_pull)
+            .chain(&self.health.required_images)
+            .chain(self.pool.sizes.keys())
+            .cloned()
+            .collect()
+    }
+
+    // The sections that are only read at startup keep their current values.
+    // Returns the names of the sections whose changes are ignored.
+    pub fn keep_static_sections(&mut self, current: &Config) -> Vec<&'static str> {
+        let mut ignored = Vec::new();
+
+        keep(&mut ignored, "server", &mut self.server, &current.server);
+        keep(&mut ignored, "auth", &mut self.auth, &current.auth);
+        keep(
+            &mut ignored,
+            "rate limit",
+            &mut self.rate_limit,
+            &current.rate_limit,
+        );
+        keep(
+            &mut ignored,
+            "unix socket",
+            &mut self.unix_socket,
+            &current.unix_socket,
+        );
+        keep(&mut ignored, "queue", &mut self.queue, &current.queue);
+        keep(&mut ignored, "pool", &mut self.pool, &current.pool);
+        keep(&mut ignored, "reaper", &mut self.reaper, &current.reaper);
+        keep(&mut ignored, "metrics", &mut self.metrics, &current.metrics);
+        keep(&mut ignored, "jobs", &mut self.jobs, &current.jobs);
+
+        // A random instance id is generated when none is configured
+        self.container.instance_id = current.container.instance_id.clone();
+
+        ignored
+    }
+}
+
+fn keep<T: Clone + PartialEq>(
+    ignored: &mut Vec<&'static str>,
+    name: &'static str,
+    new: &mut T,
+    current: &T,
+) {
+    if new != current {
+        ignored.push(name);
+        *new = current.clone();
+    }
+}
+
+// The config handed to the handlers, it's replaced when the config is reloaded.
+// Handlers load the config once per request, so in-flight runs keep the config they started with.
+pub struct Shared {
+    current: RwLock<Arc<Config>>,
+}
+
+impl Shared {
+    pub fn new(config: Config) -> Shared {
+        Shared {
+            current: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    pub fn load(&self) -> Arc<Config> {
+        let current = self.current.read().unwrap_or_else(|err| err.into_inner());
+        Arc::clone(&current)
+    }
+
+    pub fn store(&self, config: Config) {
+        let mut current = self.current.write().unwrap_or_else(|err| err.into_inner());
+        *current = Arc::new(config);
+    }
 }
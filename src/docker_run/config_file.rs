@@ -0,0 +1,241 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::docker_run::environment;
+
+// Reads a toml or json config file into environment keys.
+// Nested keys are joined with underscores and uppercased, so server.listen_port
+// (or listen_port in a [server] table) is the same as SERVER_LISTEN_PORT.
+// Lists are joined with spaces like the space separated environment variables.
+pub fn read(path: &Path) -> Result<environment::Environment, Error> {
+    let data = fs::read_to_string(path).map_err(Error::ReadFile)?;
+
+    let value = if is_json(path) {
+        serde_json::from_str(&data).map_err(Error::ParseJson)?
+    } else {
+        let value: toml::Value = toml::from_str(&data).map_err(Error::ParseToml)?;
+        serde_json::to_value(value).map_err(Error::ParseJson)?
+    };
+
+    let mut environment = environment::Environment::default();
+    flatten(path, &mut environment, &[], &value)?;
+
+    Ok(environment)
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| extension == "json")
+        .unwrap_or(false)
+}
+
+fn flatten(
+    path: &Path,
+    environment: &mut environment::Environment,
+    key_path: &[String],
+    value: &serde_json::Value,
+) -> Result<(), Error> {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                let mut key_path = key_path.to_vec();
+                key_path.push(key.clone());
+                flatten(path, environment, &key_path, value)?;
+            }
+
+            Ok(())
+        }
+
+        _ => {
+            let file_key = key_path.join(".");
+
+            let string_value = to_string_value(value).ok_or_else(|| Error::UnsupportedValue {
+                key: file_key.clone(),
+            })?;
+
+            environment.insert(
+                key_path.join("_").to_uppercase(),
+                string_value,
+                environment::Source::File {
+                    path: path.to_path_buf(),
+                    key: file_key,
+                },
+            );
+
+            Ok(())
+        }
+    }
+}
+
+fn to_string_value(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(s) => Some(s.clone()),
+
+        serde_json::Value::Bool(b) => Some(b.to_string()),
+
+        serde_json::Value::Number(n) => Some(n.to_string()),
+
+        serde_json::Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                serde_json::Value::Array(_) | serde_json::Value::Object(_) => None,
+                _ => to_string_value(value),
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(" ")),
+
+        serde_json::Value::Null | serde_json::Value::Object(_) => None,
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    ReadFile(io::Error),
+    ParseToml(toml::de::Error),
+    ParseJson(serde_json::Error),
+    UnsupportedValue { key: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ReadFile(err) => {
+                write!(f, "Failed to read config file: {}", err)
+            }
+
+            Error::ParseToml(err) => {
+                write!(f, "Failed to parse toml config file: {}", err)
+            }
+
+            Error::ParseJson(err) => {
+                write!(f, "Failed to parse json config file: {}", err)
+            }
+
+            Error::UnsupportedValue { key } => {
+                write!(
+                    f,
+                    "Unsupported value for key «{}» in config file, expected a string, number, boolean or list",
+                    key
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::process;
+
+    // Tests run in parallel, so every test uses its own file name
+    fn read_str(
+        name: &str,
+        extension: &str,
+        data: &str,
+    ) -> Result<environment::Environment, Error> {
+        let path: PathBuf = std::env::temp_dir().join(format!(
+            "docker-run-{}-{}.{}",
+            process::id(),
+            name,
+            extension
+        ));
+
+        fs::write(&path, data).unwrap();
+        let result = read(&path);
+        fs::remove_file(&path).unwrap();
+
+        result
+    }
+
+    #[test]
+    fn read_toml_tables() {
+        let environment = read_str(
+            "tables",
+            "toml",
+            r#"
+            [server]
+            listen_port = 8088
+
+            [docker.container]
+            network_disabled = true
+            cap_drop = ["MKNOD", "NET_RAW"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(environment.get("SERVER_LISTEN_PORT").unwrap(), "8088");
+        assert_eq!(
+            environment
+                .get("DOCKER_CONTAINER_NETWORK_DISABLED")
+                .unwrap(),
+            "true"
+        );
+        assert_eq!(
+            environment.get("DOCKER_CONTAINER_CAP_DROP").unwrap(),
+            "MKNOD NET_RAW"
+        );
+    }
+
+    #[test]
+    fn read_json_with_dotted_and_nested_keys() {
+        let environment = read_str(
+            "nested",
+            "json",
+            r#"{"run": {"max_execution_time": 15}, "reaper_interval": "30"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(environment.get("RUN_MAX_EXECUTION_TIME").unwrap(), "15");
+        assert_eq!(environment.get("REAPER_INTERVAL").unwrap(), "30");
+
+        match environment.source("RUN_MAX_EXECUTION_TIME") {
+            environment::Source::File { key, .. } => assert_eq!(key, "run.max_execution_time"),
+            source => panic!("Unexpected source: {}", source),
+        }
+    }
+
+    #[test]
+    fn read_rejects_unsupported_values() {
+        let result = read_str("null", "json", r#"{"server": {"listen_port": null}}"#);
+        assert!(
+            matches!(result, Err(Error::UnsupportedValue { key }) if key == "server.listen_port")
+        );
+
+        let result = read_str(
+            "nested-list",
+            "json",
+            r#"{"docker": {"container": {"cap_drop": [["x"]]}}}"#,
+        );
+        assert!(matches!(result, Err(Error::UnsupportedValue { .. })));
+    }
+
+    #[test]
+    fn unread_keys_are_reported() {
+        let environment = read_str(
+            "unread",
+            "toml",
+            r#"
+            [server]
+            listen_port = 8088
+            listen_prot = 8089
+            "#,
+        )
+        .unwrap();
+
+        environment.get("SERVER_LISTEN_PORT");
+
+        let unread: Vec<String> = environment
+            .unread_file_keys()
+            .into_iter()
+            .map(|err| match err {
+                environment::Error::UnusedKey { key, .. } => key,
+                err => panic!("Unexpected error: {}", err),
+            })
+            .collect();
+
+        assert_eq!(unread, vec!["SERVER_LISTEN_PROT".to_string()]);
+    }
+}
@@ -1,6 +1,6 @@
 use crate::docker_run::http_extra;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryInto;
 use std::fmt;
 use std::io;
@@ -18,7 +18,10 @@ pub struct ContainerConfig {
     pub open_stdin: bool,
     pub stdin_once: bool,
     pub image: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cmd: Option<Vec<String>>,
     pub network_disabled: bool,
+    pub labels: HashMap<String, String>,
     pub host_config: HostConfig,
 }
 
@@ -32,6 +35,8 @@ pub struct HostConfig {
     pub ulimits: Vec<Ulimit>,
     pub readonly_rootfs: bool,
     pub tmpfs: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -130,6 +135,177 @@ pub fn version<Stream: Read + Write>(
     http_extra::send_request(stream, req).map_err(Error::SendRequest)
 }
 
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all(deserialize = "PascalCase"))]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct InfoResponse {
+    #[serde(default)]
+    pub default_runtime: String,
+    #[serde(default)]
+    pub runtimes: BTreeMap<String, serde_json::Value>,
+}
+
+pub fn info_request() -> Result<http::Request<http_extra::Body>, http::Error> {
+    http::Request::get("/info")
+        .header("Accept", "application/json")
+        .header("Host", "127.0.0.1")
+        .header("Connection", "close")
+        .body(http_extra::Body::Empty())
+}
+
+pub fn info<Stream: Read + Write>(stream: Stream) -> Result<http::Response<InfoResponse>, Error> {
+    let req = info_request().map_err(|x| Error::PrepareRequest(PrepareRequestError::Request(x)))?;
+
+    http_extra::send_request(stream, req).map_err(Error::SendRequest)
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PullProgressResponse {
+    #[serde(default)]
+    pub status: Option<String>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+pub fn pull_image_request(image: &str) -> Result<http::Request<http_extra::Body>, http::Error> {
+    let url = format!("/images/create?fromImage={}", percent_encode(image));
+
+    http::Request::post(url)
+        .header("Accept", "application/json")
+        .header("Host", "127.0.0.1")
+        .header("Connection", "close")
+        .body(http_extra::Body::Empty())
+}
+
+// Pulls the image and returns the progress messages.
+// The response status is 200 even if the pull fails, failures are reported in the progress messages.
+pub fn pull_image<Stream: Read + Write>(
+    stream: Stream,
+    image: &str,
+) -> Result<Vec<PullProgressResponse>, Error> {
+    let req = pull_image_request(image)
+        .map_err(|x| Error::PrepareRequest(PrepareRequestError::Request(x)))?;
+
+    let response = http_extra::send_request_raw(stream, req).map_err(Error::SendRequest)?;
+
+    serde_json::Deserializer::from_slice(response.body())
+        .into_iter()
+        .collect::<Result<_, _>>()
+        .map_err(|err| Error::SendRequest(http_extra::Error::DeserializeBody(err)))
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all(deserialize = "PascalCase"))]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct ImageSummaryResponse {
+    pub id: String,
+    #[serde(default, deserialize_with = "deserialize_null_default")]
+    pub repo_tags: Vec<String>,
+    #[serde(default, deserialize_with = "deserialize_null_default")]
+    pub repo_digests: Vec<String>,
+    pub size: i64,
+    pub created: i64,
+}
+
+// Docker returns null instead of an empty list for images without tags or digests
+fn deserialize_null_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(|value| value.unwrap_or_default())
+}
+
+pub fn list_images_request() -> Result<http::Request<http_extra::Body>, http::Error> {
+    http::Request::get("/images/json")
+        .header("Accept", "application/json")
+        .header("Host", "127.0.0.1")
+        .header("Connection", "close")
+        .body(http_extra::Body::Empty())
+}
+
+pub fn list_images<Stream: Read + Write>(
+    stream: Stream,
+) -> Result<http::Response<Vec<ImageSummaryResponse>>, Error> {
+    let req = list_images_request()
+        .map_err(|x| Error::PrepareRequest(PrepareRequestError::Request(x)))?;
+
+    http_extra::send_request(stream, req).map_err(Error::SendRequest)
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all(deserialize = "PascalCase"))]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct ImageDeleteResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub untagged: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted: Option<String>,
+}
+
+pub fn remove_image_request(image: &str) -> Result<http::Request<http_extra::Body>, http::Error> {
+    let url = format!("/images/{}", percent_encode(image));
+
+    http::Request::delete(url)
+        .header("Accept", "application/json")
+        .header("Host", "127.0.0.1")
+        .header("Connection", "close")
+        .body(http_extra::Body::Empty())
+}
+
+pub fn remove_image<Stream: Read + Write>(
+    stream: Stream,
+    image: &str,
+) -> Result<http::Response<Vec<ImageDeleteResponse>>, Error> {
+    let req = remove_image_request(image)
+        .map_err(|x| Error::PrepareRequest(PrepareRequestError::Request(x)))?;
+
+    http_extra::send_request(stream, req).map_err(Error::SendRequest)
+}
+
+pub fn ping_request() -> Result<http::Request<http_extra::Body>, http::Error> {
+    http::Request::get("/_ping")
+        .header("Host", "127.0.0.1")
+        .header("Connection", "close")
+        .body(http_extra::Body::Empty())
+}
+
+pub fn ping<Stream: Read + Write>(stream: Stream) -> Result<http::Response<Vec<u8>>, Error> {
+    let req = ping_request().map_err(|x| Error::PrepareRequest(PrepareRequestError::Request(x)))?;
+
+    http_extra::send_request_raw(stream, req).map_err(Error::SendRequest)
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all(deserialize = "PascalCase"))]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct ImageInspectResponse {
+    pub id: String,
+    #[serde(default)]
+    pub repo_digests: Vec<String>,
+}
+
+pub fn inspect_image_request(image: &str) -> Result<http::Request<http_extra::Body>, http::Error> {
+    let url = format!("/images/{}/json", percent_encode(image));
+
+    http::Request::get(url)
+        .header("Accept", "application/json")
+        .header("Host", "127.0.0.1")
+        .header("Connection", "close")
+        .body(http_extra::Body::Empty())
+}
+
+pub fn inspect_image<Stream: Read + Write>(
+    stream: Stream,
+    image: &str,
+) -> Result<http::Response<ImageInspectResponse>, Error> {
+    let req = inspect_image_request(image)
+        .map_err(|x| Error::PrepareRequest(PrepareRequestError::Request(x)))?;
+
+    http_extra::send_request(stream, req).map_err(Error::SendRequest)
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 #[serde(rename_all(deserialize = "PascalCase"))]
 #[serde(rename_all(serialize = "camelCase"))]
@@ -162,6 +338,56 @@ pub fn create_container<Stream: Read + Write>(
     http_extra::send_request(stream, req).map_err(Error::SendRequest)
 }
 
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all(deserialize = "PascalCase"))]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct ContainerSummaryResponse {
+    pub id: String,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+}
+
+pub fn list_containers_request(
+    label: &str,
+) -> Result<http::Request<http_extra::Body>, PrepareRequestError> {
+    let filters = serde_json::json!({ "label": [label] });
+    let filters = serde_json::to_string(&filters).map_err(PrepareRequestError::SerializeBody)?;
+    let url = format!(
+        "/containers/json?all=1&filters={}",
+        percent_encode(&filters)
+    );
+
+    http::Request::get(url)
+        .header("Accept", "application/json")
+        .header("Host", "127.0.0.1")
+        .header("Connection", "close")
+        .body(http_extra::Body::Empty())
+        .map_err(PrepareRequestError::Request)
+}
+
+// Lists all containers, including stopped ones, that has the given label
+pub fn list_containers<Stream: Read + Write>(
+    stream: Stream,
+    label: &str,
+) -> Result<http::Response<Vec<ContainerSummaryResponse>>, Error> {
+    let req = list_containers_request(label).map_err(Error::PrepareRequest)?;
+
+    http_extra::send_request(stream, req).map_err(Error::SendRequest)
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
 pub fn start_container_request(
     container_id: &str,
 ) -> Result<http::Request<http_extra::Body>, http::Error> {
@@ -206,6 +432,71 @@ pub fn remove_container<Stream: Read + Write>(
     http_extra::send_request(stream, req).map_err(Error::SendRequest)
 }
 
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all(deserialize = "PascalCase"))]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct ContainerWaitResponse {
+    pub status_code: i64,
+}
+
+pub fn wait_container_request(
+    container_id: &str,
+) -> Result<http::Request<http_extra::Body>, http::Error> {
+    let url = format!("/containers/{}/wait", container_id);
+
+    http::Request::post(url)
+        .header("Accept", "application/json")
+        .header("Host", "127.0.0.1")
+        .header("Connection", "close")
+        .body(http_extra::Body::Empty())
+}
+
+pub fn wait_container<Stream: Read + Write>(
+    stream: Stream,
+    container_id: &str,
+) -> Result<http::Response<ContainerWaitResponse>, Error> {
+    let req = wait_container_request(container_id)
+        .map_err(|x| Error::PrepareRequest(PrepareRequestError::Request(x)))?;
+
+    http_extra::send_request(stream, req).map_err(Error::SendRequest)
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerInspectResponse {
+    pub state: ContainerStateResponse,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerStateResponse {
+    pub status: String,
+    #[serde(rename = "OOMKilled")]
+    pub oom_killed: bool,
+}
+
+pub fn inspect_container_request(
+    container_id: &str,
+) -> Result<http::Request<http_extra::Body>, http::Error> {
+    let url = format!("/containers/{}/json", container_id);
+
+    http::Request::get(url)
+        .header("Accept", "application/json")
+        .header("Host", "127.0.0.1")
+        .header("Connection", "close")
+        .body(http_extra::Body::Empty())
+}
+
+pub fn inspect_container<Stream: Read + Write>(
+    stream: Stream,
+    container_id: &str,
+) -> Result<http::Response<ContainerInspectResponse>, Error> {
+    let req = inspect_container_request(container_id)
+        .map_err(|x| Error::PrepareRequest(PrepareRequestError::Request(x)))?;
+
+    http_extra::send_request(stream, req).map_err(Error::SendRequest)
+}
+
 pub fn attach_container_request(
     container_id: &str,
 ) -> Result<http::Request<http_extra::Body>, http::Error> {
@@ -281,7 +572,15 @@ pub struct StreamOutput {
     pub stderr: Vec<u8>,
 }
 
-pub fn read_stream<R: Read>(r: R, max_read_size: usize) -> Result<StreamOutput, StreamError> {
+pub fn read_stream<R, F>(
+    r: R,
+    max_read_size: usize,
+    mut on_frame: F,
+) -> Result<StreamOutput, StreamError>
+where
+    R: Read,
+    F: FnMut(&StreamType, &[u8]),
+{
     let mut reader = iowrap::Eof::new(r);
     let mut read_size = 0;
     let mut stdin = Vec::new();
@@ -297,6 +596,15 @@ pub fn read_stream<R: Read>(r: R, max_read_size: usize) -> Result<StreamOutput,
             .read_exact(&mut buffer)
             .map_err(io_read_error_to_stream_error)?;
 
+        read_size += stream_length;
+
+        err_if_false(
+            read_size <= max_read_size,
+            StreamError::MaxReadSize(max_read_size),
+        )?;
+
+        on_frame(&stream_type, &buffer);
+
         match stream_type {
             StreamType::Stdin() => {
                 stdin.append(&mut buffer);
@@ -310,13 +618,6 @@ pub fn read_stream<R: Read>(r: R, max_read_size: usize) -> Result<StreamOutput,
                 stderr.append(&mut buffer);
             }
         }
-
-        read_size += stream_length;
-
-        err_if_false(
-            read_size <= max_read_size,
-            StreamError::MaxReadSize(max_read_size),
-        )?;
     }
 
     Ok(StreamOutput {
@@ -343,13 +644,21 @@ fn err_if_false<E>(value: bool, err: E) -> Result<(), E> {
 }
 
 #[derive(Debug)]
-enum StreamType {
+pub enum StreamType {
     Stdin(),
     Stdout(),
     Stderr(),
 }
 
 impl StreamType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamType::Stdin() => "stdin",
+            StreamType::Stdout() => "stdout",
+            StreamType::Stderr() => "stderr",
+        }
+    }
+
     fn from_byte(n: u8) -> Option<StreamType> {
         match n {
             0 => Some(StreamType::Stdin()),
@@ -0,0 +1,192 @@
+use std::fmt;
+
+use crate::docker_run::allowlist;
+use crate::docker_run::config;
+use crate::docker_run::docker;
+use crate::docker_run::images;
+use crate::docker_run::unix_stream;
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub message: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    Ok,
+    Warning,
+    Failed,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self.status {
+            Status::Ok => "ok",
+            Status::Warning => "warning",
+            Status::Failed => "failed",
+        };
+
+        write!(f, "[{}] {}: {}", status, self.name, self.message)
+    }
+}
+
+// Checks the docker daemon and the images referenced by the config.
+// The remaining checks are skipped if docker can't be reached.
+pub fn run(config: &config::Config) -> Vec<Check> {
+    let mut checks = vec![check_version(&config.unix_socket)];
+
+    if checks[0].status == Status::Failed {
+        return checks;
+    }
+
+    checks.push(check_runtime(
+        &config.unix_socket,
+        config.container.runtime.as_deref(),
+    ));
+
+    for image in config.configured_images() {
+        checks.push(check_image(config, &image));
+    }
+
+    checks
+}
+
+fn check_version(stream_config: &unix_stream::Config) -> Check {
+    let result = unix_stream::with_stream(stream_config, Error::UnixStream, |stream| {
+        docker::version(stream).map_err(Error::Docker)
+    });
+
+    match result {
+        Ok(response) => {
+            let version = response.body();
+
+            Check::ok(
+                "docker.version",
+                format!(
+                    "Docker {} (api {}, {}/{}, kernel {})",
+                    version.version,
+                    version.api_version,
+                    version.os,
+                    version.arch,
+                    version.kernel_version
+                ),
+            )
+        }
+
+        Err(err) => Check::failed("docker.version", err.to_string()),
+    }
+}
+
+// Containers use DOCKER_CONTAINER_RUNTIME if it's set, it must be known to the daemon
+fn check_runtime(stream_config: &unix_stream::Config, configured: Option<&str>) -> Check {
+    let result = unix_stream::with_stream(stream_config, Error::UnixStream, |stream| {
+        docker::info(stream).map_err(Error::Docker)
+    });
+
+    let info = match result {
+        Ok(response) => response.into_body(),
+        Err(err) => return Check::failed("docker.runtime", err.to_string()),
+    };
+
+    let runtimes: Vec<&str> = info.runtimes.keys().map(|name| name.as_str()).collect();
+
+    match configured {
+        Some(runtime) if !info.runtimes.contains_key(runtime) => Check::failed(
+            "docker.runtime",
+            format!(
+                "DOCKER_CONTAINER_RUNTIME is {}, but the daemon doesn't have it (available: {})",
+                runtime,
+                runtimes.join(", ")
+            ),
+        ),
+
+        Some(runtime) => Check::ok(
+            "docker.runtime",
+            format!(
+                "Containers use the configured runtime {} (default: {}, available: {})",
+                runtime,
+                info.default_runtime,
+                runtimes.join(", ")
+            ),
+        ),
+
+        None => Check::ok(
+            "docker.runtime",
+            format!(
+                "Containers use the default runtime {} (available: {})",
+                info.default_runtime,
+                runtimes.join(", ")
+            ),
+        ),
+    }
+}
+
+// A missing image is only a problem if it's not pulled on demand
+fn check_image(config: &config::Config, image: &str) -> Check {
+    let name = format!("image {}", image);
+
+    let reference = match allowlist::check(&config.allowlist, image) {
+        Ok(reference) => reference,
+        Err(err) => return Check::failed(&name, err.to_string()),
+    };
+
+    match images::image_exists(&config.unix_socket, &reference) {
+        Ok(true) => match allowlist::verify_digest(&config.allowlist, &config.unix_socket, image) {
+            Ok(()) => Check::ok(&name, "Available".to_string()),
+            Err(err) => Check::failed(&name, err.to_string()),
+        },
+
+        Ok(false) => match config.images.pull_policy {
+            images::PullPolicy::Never => {
+                Check::failed(&name, "Missing and IMAGE_PULL_POLICY is never".to_string())
+            }
+
+            _ => Check::warning(&name, "Missing, it will be pulled on first use".to_string()),
+        },
+
+        Err(err) => Check::failed(&name, err.to_string()),
+    }
+}
+
+impl Check {
+    fn ok(name: &str, message: String) -> Check {
+        Check::new(name, Status::Ok, message)
+    }
+
+    fn warning(name: &str, message: String) -> Check {
+        Check::new(name, Status::Warning, message)
+    }
+
+    fn failed(name: &str, message: String) -> Check {
+        Check::new(name, Status::Failed, message)
+    }
+
+    fn new(name: &str, status: Status, message: String) -> Check {
+        Check {
+            name: name.to_string(),
+            status,
+            message,
+        }
+    }
+}
+
+enum Error {
+    UnixStream(unix_stream::Error),
+    Docker(docker::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnixStream(err) => {
+                write!(f, "Unix socket failure: {}", err)
+            }
+
+            Error::Docker(err) => {
+                write!(f, "Docker request failed: {}", err)
+            }
+        }
+    }
+}
@@ -1,31 +1,122 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fmt;
+use std::path::PathBuf;
 use std::str::FromStr;
 
-pub type Environment = HashMap<String, String>;
+// Configuration values by environment key, i.e. SERVER_LISTEN_PORT.
+// Values come from environment variables or from a config file.
+#[derive(Clone, Debug, Default)]
+pub struct Environment {
+    values: HashMap<String, Value>,
+    // Keys that were looked up, keys of the config file that are never looked up are reported
+    read_keys: RefCell<HashSet<String>>,
+}
+
+#[derive(Clone, Debug)]
+struct Value {
+    value: String,
+    source: Source,
+}
+
+// Where a value was read from, used in error messages
+#[derive(Clone, Debug)]
+pub enum Source {
+    Environment(),
+    File { path: PathBuf, key: String },
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Environment() => write!(f, "environment"),
+
+            Source::File { path, key } => {
+                write!(f, "config file {}, key: «{}»", path.display(), key)
+            }
+        }
+    }
+}
+
+impl Environment {
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.read_keys.borrow_mut().insert(key.to_string());
+        self.values.get(key).map(|value| &value.value)
+    }
+
+    // Config file keys that no config reader looked up, they are usually misspelled.
+    // Some settings are only read when another one is set, i.e. the tls files for a tcp DOCKER_HOST.
+    pub fn unread_file_keys(&self) -> Vec<Error> {
+        let read_keys = self.read_keys.borrow();
+
+        let mut errors: Vec<(&String, &Source)> = self
+            .values
+            .iter()
+            .filter(|(key, value)| {
+                matches!(value.source, Source::File { .. }) && !read_keys.contains(*key)
+            })
+            .map(|(key, value)| (key, &value.source))
+            .collect();
+
+        errors.sort_by_key(|(key, _)| *key);
+
+        errors
+            .into_iter()
+            .map(|(key, source)| Error::UnusedKey {
+                key: key.clone(),
+                source: source.clone(),
+            })
+            .collect()
+    }
+
+    pub fn source(&self, key: &str) -> Source {
+        self.values
+            .get(key)
+            .map(|value| value.source.clone())
+            .unwrap_or(Source::Environment())
+    }
+
+    pub fn insert(&mut self, key: String, value: String, source: Source) {
+        self.values.insert(key, Value { value, source });
+    }
+
+    // Values of self take precedence over the values of other
+    pub fn merged_over(mut self, other: Environment) -> Environment {
+        for (key, value) in other.values {
+            self.values.entry(key).or_insert(value);
+        }
+
+        self
+    }
+}
 
 pub fn get_environment() -> Environment {
-    env::vars().collect()
+    let mut environment = Environment::default();
+
+    for (key, value) in env::vars() {
+        environment.insert(key, value, Source::Environment());
+    }
+
+    environment
 }
 
-pub fn lookup<T>(environment: &Environment, key: &'static str) -> Result<T, Error>
+pub fn lookup<T>(environment: &Environment, key: &str) -> Result<T, Error>
 where
     T: FromStr,
     T::Err: fmt::Display,
 {
     environment
         .get(key)
-        .ok_or(Error::KeyNotFound(key))
+        .ok_or_else(|| Error::KeyNotFound(key.to_string()))
         .and_then(|string_value| {
-            string_value.parse::<T>().map_err(|err| Error::Parse {
-                key,
-                details: err.to_string(),
-            })
+            string_value
+                .parse::<T>()
+                .map_err(|err| parse_error(environment, key, err.to_string()))
         })
 }
 
-pub fn lookup_optional<T>(environment: &Environment, key: &'static str) -> Result<Option<T>, Error>
+pub fn lookup_optional<T>(environment: &Environment, key: &str) -> Result<Option<T>, Error>
 where
     T: FromStr,
     T::Err: fmt::Display,
@@ -36,17 +127,119 @@ where
         Some(string_value) => string_value
             .parse::<T>()
             .map(Some)
-            .map_err(|err| Error::Parse {
-                key,
-                details: err.to_string(),
-            }),
+            .map_err(|err| parse_error(environment, key, err.to_string())),
+    }
+}
+
+// Reads values and collects the errors instead of stopping at the first one,
+// so that all invalid values can be reported at once
+pub struct Reader<'a> {
+    environment: &'a Environment,
+    errors: Vec<Error>,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(environment: &'a Environment) -> Reader<'a> {
+        Reader {
+            environment,
+            errors: vec![],
+        }
+    }
+
+    pub fn is_set(&self, key: &str) -> bool {
+        self.environment.get(key).is_some()
+    }
+
+    // Returns None if the value is missing or invalid, the error is collected
+    pub fn lookup<T>(&mut self, key: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let result = lookup(self.environment, key);
+        self.collect(result)
+    }
+
+    // Returns None if the value is missing or invalid, only invalid values are errors
+    pub fn lookup_optional<T>(&mut self, key: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let result = lookup_optional(self.environment, key);
+        self.collect(result).flatten()
+    }
+
+    // Returns the default if the value is missing or invalid, only invalid values are errors
+    pub fn lookup_or<T>(&mut self, key: &str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.lookup_optional(key).unwrap_or(default)
+    }
+
+    // Checks a value that was read, the details of a failed check are reported for the key
+    pub fn validate<T, F>(&mut self, key: &str, value: Option<T>, validate: F) -> Option<T>
+    where
+        F: FnOnce(&T) -> Result<(), String>,
+    {
+        let value = value?;
+
+        match validate(&value) {
+            Ok(()) => Some(value),
+
+            Err(details) => {
+                self.error(parse_error(self.environment, key, details));
+                None
+            }
+        }
+    }
+
+    pub fn collect<T>(&mut self, result: Result<T, Error>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+
+            Err(err) => {
+                self.error(err);
+                None
+            }
+        }
+    }
+
+    pub fn error(&mut self, err: Error) {
+        self.errors.push(err);
+    }
+
+    pub fn parse_error(&self, key: &str, details: String) -> Error {
+        parse_error(self.environment, key, details)
+    }
+
+    pub fn into_errors(self) -> Vec<Error> {
+        self.errors
+    }
+}
+
+pub fn parse_error(environment: &Environment, key: &str, details: String) -> Error {
+    Error::Parse {
+        key: key.to_string(),
+        source: environment.source(key),
+        details,
     }
 }
 
 #[derive(Debug)]
 pub enum Error {
-    KeyNotFound(&'static str),
-    Parse { key: &'static str, details: String },
+    KeyNotFound(String),
+    Parse {
+        key: String,
+        source: Source,
+        details: String,
+    },
+    UnusedKey {
+        key: String,
+        source: Source,
+    },
 }
 
 impl fmt::Display for Error {
@@ -54,11 +247,31 @@ impl fmt::Display for Error {
         match self {
             Error::KeyNotFound(key) => write!(f, "Environment key not found: «{0}»", key),
 
-            Error::Parse { key, details } => write!(
+            Error::Parse {
+                key,
+                source: Source::Environment(),
+                details,
+            } => write!(
                 f,
                 "Failed to parse value for environment key: «{0}», details: {1}",
                 key, details
             ),
+
+            Error::Parse {
+                key,
+                source,
+                details,
+            } => write!(
+                f,
+                "Failed to parse value for «{0}» from {1}, details: {2}",
+                key, source, details
+            ),
+
+            Error::UnusedKey { key, source } => write!(
+                f,
+                "Unused key in {0}, «{1}» is not a setting or is not used with this config",
+                source, key
+            ),
         }
     }
 }
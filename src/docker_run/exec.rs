@@ -0,0 +1,123 @@
+use serde_json::{Map, Value};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use crate::docker_run::api;
+use crate::docker_run::cli;
+use crate::docker_run::config;
+use crate::docker_run::docker;
+use crate::docker_run::images;
+use crate::docker_run::run;
+use crate::docker_run::tracker;
+
+// Exit codes of the exec command by error category
+pub const EXIT_PREPARE: i32 = 1;
+pub const EXIT_LIMITS: i32 = 3;
+pub const EXIT_DOCKER: i32 = 4;
+pub const EXIT_CODE_RUNNER: i32 = 5;
+
+// Runs a payload the same way as the /run endpoint, but without the queue and the pool
+pub fn run(config: &config::Config, args: cli::ExecArgs) -> Result<Map<String, Value>, Error> {
+    let payload = read_payload(&args.payload_path)?;
+
+    let req_body = api::run::RequestBody {
+        image: args.image,
+        language: args.language,
+        mode: api::run::Mode::Json,
+        payload: Some(payload),
+        cmd: None,
+        stdin: None,
+        stdin_base64: None,
+        limits: None,
+    };
+
+    let prepared =
+        api::run::prepare(config, &images::PullHistory::new(), req_body).map_err(Error::Prepare)?;
+
+    let result = run::run(
+        config.unix_socket.clone(),
+        prepared.run_request,
+        config.debug.clone(),
+        &tracker::Tracker::new(),
+    )
+    .map_err(Error::Run)?;
+
+    Ok(api::run::build_result(
+        result.output,
+        &result.container_state,
+        Some(&result.timings),
+        None,
+    ))
+}
+
+fn read_payload(path: &Path) -> Result<Map<String, Value>, Error> {
+    let data = if path == Path::new("-") {
+        let mut data = Vec::new();
+        io::stdin()
+            .read_to_end(&mut data)
+            .map_err(Error::ReadPayload)?;
+        data
+    } else {
+        fs::read(path).map_err(Error::ReadPayload)?
+    };
+
+    serde_json::from_slice(&data).map_err(Error::ParsePayload)
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::ReadPayload(_) => EXIT_PREPARE,
+            Error::ParsePayload(_) => EXIT_PREPARE,
+            Error::Prepare(_) => EXIT_PREPARE,
+
+            Error::Run(err) => match err {
+                run::Error::OutOfMemory(_) => EXIT_LIMITS,
+
+                run::Error::ReadStream(docker::StreamError::MaxExecutionTime()) => EXIT_LIMITS,
+
+                run::Error::ReadStream(docker::StreamError::MaxReadSize(_)) => EXIT_LIMITS,
+
+                run::Error::SerializePayload(_)
+                | run::Error::StreamStdinUnexpected(_)
+                | run::Error::StreamStderr(_)
+                | run::Error::StreamStdoutDecode(_)
+                | run::Error::StreamStdoutReservedKey(_) => EXIT_CODE_RUNNER,
+
+                _ => EXIT_DOCKER,
+            },
+        }
+    }
+}
+
+pub enum Error {
+    ReadPayload(io::Error),
+    ParsePayload(serde_json::Error),
+    Prepare(api::ErrorResponse),
+    Run(run::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ReadPayload(err) => {
+                write!(f, "Failed to read payload: {}", err)
+            }
+
+            Error::ParsePayload(err) => {
+                write!(f, "Failed to parse payload: {}", err)
+            }
+
+            Error::Prepare(err) => {
+                write!(f, "{} ({})", err.body.message, err.body.error)
+            }
+
+            Error::Run(err) => {
+                write!(f, "{}", err)
+            }
+        }
+    }
+}
@@ -69,12 +69,27 @@ impl fmt::Display for Error {
 }
 
 pub fn send_request<Stream, ResponseBody>(
-    mut stream: Stream,
+    stream: Stream,
     req: Request<Body>,
 ) -> Result<Response<ResponseBody>, Error>
 where
     Stream: Read + Write,
     ResponseBody: DeserializeOwned,
+{
+    let (response_parts, raw_body) = send_request_raw(stream, req)?.into_parts();
+
+    let body = serde_json::from_slice(&raw_body).map_err(Error::DeserializeBody)?;
+
+    Ok(Response::from_parts(response_parts, body))
+}
+
+// Sends the request and returns the response body without deserializing it
+pub fn send_request_raw<Stream>(
+    mut stream: Stream,
+    req: Request<Body>,
+) -> Result<Response<Vec<u8>>, Error>
+where
+    Stream: Read + Write,
 {
     write_request_head(&mut stream, &req).map_err(Error::WriteRequest)?;
 
@@ -103,9 +118,7 @@ where
         Error::BadStatus(response_parts.status, raw_body.clone()),
     )?;
 
-    let body = serde_json::from_slice(&raw_body).map_err(Error::DeserializeBody)?;
-
-    Ok(Response::from_parts(response_parts, body))
+    Ok(Response::from_parts(response_parts, raw_body))
 }
 
 fn read_response_body<R: BufRead>(
@@ -197,7 +210,7 @@ fn get_content_length(headers: &header::HeaderMap<header::HeaderValue>) -> usize
 enum TransferEncoding {
     NoEncoding(),
     Chunked(),
-    Other(String),
+    Other(),
 }
 
 impl TransferEncoding {
@@ -207,7 +220,7 @@ impl TransferEncoding {
 
             "" => TransferEncoding::NoEncoding(),
 
-            other => TransferEncoding::Other(other.to_string()),
+            _ => TransferEncoding::Other(),
         }
     }
 }
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::docker_run::allowlist;
+use crate::docker_run::docker;
+use crate::docker_run::http_extra;
+use crate::docker_run::unix_stream;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub pull_policy: PullPolicy,
+    // Images pulled at startup, the service is not ready until they are pulled
+    pub pre_pull: Vec<String>,
+    pub pull_timeout: Duration,
+    // How long an image pulled by the always policy is used before it's pulled again,
+    // and how long the if-missing policy trusts an image to exist before inspecting it again
+    pub pull_interval: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PullPolicy {
+    // Images must already exist on the host
+    Never,
+    // Images are pulled the first time they are used
+    IfMissing,
+    // Images are pulled before a run if they were not pulled within the pull interval
+    Always,
+}
+
+impl FromStr for PullPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(PullPolicy::Never),
+            "if-missing" => Ok(PullPolicy::IfMissing),
+            "always" => Ok(PullPolicy::Always),
+            _ => Err(format!(
+                "Unknown pull policy: {} (expected never, if-missing or always)",
+                s
+            )),
+        }
+    }
+}
+
+// When images were last pulled or found by ensure_image
+#[derive(Default)]
+pub struct PullHistory {
+    checked_at: Mutex<HashMap<String, Instant>>,
+    // Concurrent runs of an image wait for the pull that is already running instead of pulling it again
+    image_locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    // Runs pull their image before they enter the queue, shutdown waits for these pulls too
+    in_flight: AtomicUsize,
+    // Successful pulls, image ids cached by the pool are outdated when this changes
+    pulled: AtomicU64,
+}
+
+impl PullHistory {
+    pub fn new() -> PullHistory {
+        PullHistory::default()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn pulled(&self) -> u64 {
+        self.pulled.load(Ordering::SeqCst)
+    }
+
+    pub fn pull(
+        &self,
+        config: &Config,
+        stream_config: &unix_stream::Config,
+        image: &str,
+    ) -> Result<(), Error> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let result = pull(config, stream_config, image);
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+        if result.is_ok() {
+            self.pulled.fetch_add(1, Ordering::SeqCst);
+        }
+
+        result
+    }
+
+    // Removed images must be looked up again, the history is keyed by tags and digests
+    pub fn clear(&self) {
+        self.checked_at
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clear();
+    }
+
+    fn checked_within(&self, image: &str, interval: Duration) -> bool {
+        self.checked_at
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .get(&allowlist::normalize_image_name(image))
+            .is_some_and(|checked_at| checked_at.elapsed() < interval)
+    }
+
+    fn record(&self, image: &str) {
+        self.checked_at
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert(allowlist::normalize_image_name(image), Instant::now());
+    }
+
+    fn image_lock(&self, image: &str) -> Arc<Mutex<()>> {
+        self.image_locks
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .entry(allowlist::normalize_image_name(image))
+            .or_default()
+            .clone()
+    }
+}
+
+// Makes sure the image exists locally according to the pull policy
+pub fn ensure_image(
+    config: &Config,
+    history: &PullHistory,
+    stream_config: &unix_stream::Config,
+    image: &str,
+) -> Result<(), Error> {
+    if config.pull_policy == PullPolicy::Never
+        || history.checked_within(image, config.pull_interval)
+    {
+        return Ok(());
+    }
+
+    let image_lock = history.image_lock(image);
+    let _guard = image_lock.lock().unwrap_or_else(|err| err.into_inner());
+
+    // The image was pulled by another run while this one waited for the lock
+    if history.checked_within(image, config.pull_interval) {
+        return Ok(());
+    }
+
+    match config.pull_policy {
+        PullPolicy::IfMissing if image_exists(stream_config, image)? => (),
+
+        _ => history.pull(config, stream_config, image)?,
+    }
+
+    history.record(image);
+
+    Ok(())
+}
+
+pub fn image_exists(stream_config: &unix_stream::Config, image: &str) -> Result<bool, Error> {
+    let result = unix_stream::with_stream(stream_config, Error::UnixStream, |stream| {
+        docker::inspect_image(stream, image).map_err(Error::InspectImage)
+    });
+
+    match result {
+        Ok(_) => Ok(true),
+
+        Err(Error::InspectImage(docker::Error::SendRequest(http_extra::Error::BadStatus(
+            status,
+            _,
+        )))) if status == http::StatusCode::NOT_FOUND => Ok(false),
+
+        Err(err) => Err(err),
+    }
+}
+
+// The id of the local image, it changes when a tag is pulled again or moved
+pub fn image_id(stream_config: &unix_stream::Config, image: &str) -> Result<String, Error> {
+    let response = unix_stream::with_stream(stream_config, Error::UnixStream, |stream| {
+        docker::inspect_image(stream, image).map_err(Error::InspectImage)
+    })?;
+
+    Ok(response.body().id.clone())
+}
+
+fn pull(config: &Config, stream_config: &unix_stream::Config, image: &str) -> Result<(), Error> {
+    // Docker pulls all tags of the image if no tag is given
+    let image = allowlist::normalize_image_name(image);
+
+    // Pulling can take a lot longer than other docker calls
+    let pull_stream_config = unix_stream::Config {
+        read_timeout: config.pull_timeout,
+        ..stream_config.clone()
+    };
+
+    log::info!("Pulling image {}", image);
+
+    let progress = unix_stream::with_stream(&pull_stream_config, Error::UnixStream, |stream| {
+        docker::pull_image(stream, &image).map_err(Error::PullImage)
+    })?;
+
+    match progress.iter().find_map(|progress| progress.error.clone()) {
+        Some(message) => Err(Error::PullFailed { image, message }),
+
+        None => {
+            log::info!("Pulled image {}", image);
+            Ok(())
+        }
+    }
+}
+
+// Pulls the configured images, returns false if any of them failed
+pub fn pre_pull(
+    config: &Config,
+    history: &PullHistory,
+    stream_config: &unix_stream::Config,
+) -> bool {
+    config.pre_pull.iter().fold(true, |all_pulled, image| {
+        match history.pull(config, stream_config, image) {
+            Ok(()) => all_pulled,
+
+            Err(err) => {
+                log::error!("Failed to pre-pull image {}: {}", image, err);
+                false
+            }
+        }
+    })
+}
+
+#[derive(Debug)]
+pub enum Error {
+    UnixStream(unix_stream::Error),
+    InspectImage(docker::Error),
+    PullImage(docker::Error),
+    PullFailed { image: String, message: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnixStream(err) => {
+                write!(f, "Unix socket failure: {}", err)
+            }
+
+            Error::InspectImage(err) => {
+                write!(f, "Failed to inspect image: {}", err)
+            }
+
+            Error::PullImage(err) => {
+                write!(f, "Failed to pull image: {}", err)
+            }
+
+            Error::PullFailed { image, message } => {
+                write!(f, "Failed to pull image {}: {}", image, message)
+            }
+        }
+    }
+}
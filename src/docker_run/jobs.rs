@@ -0,0 +1,132 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub result_ttl: Duration,
+    pub max_jobs: usize,
+}
+
+#[derive(Clone, Debug)]
+pub enum Status<T> {
+    Running(),
+    Finished(T),
+}
+
+#[derive(Clone, Debug)]
+pub struct Job<T> {
+    pub id: String,
+    pub status: Status<T>,
+    created_at: Instant,
+    finished_at: Option<Instant>,
+}
+
+impl<T> Job<T> {
+    fn is_expired(&self, ttl: Duration) -> bool {
+        self.finished_at
+            .map(|finished_at| finished_at.elapsed() > ttl)
+            .unwrap_or(false)
+    }
+}
+
+pub struct Store<T> {
+    config: Config,
+    jobs: Mutex<HashMap<String, Job<T>>>,
+}
+
+impl<T: Clone> Store<T> {
+    pub fn new(config: Config) -> Store<T> {
+        Store {
+            config,
+            jobs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Registers a new running job and returns its id.
+    // Expired jobs are pruned first, then the oldest finished job is evicted if the store is full.
+    pub fn insert(&self) -> Result<String, Error> {
+        let mut jobs = self.lock();
+        prune_expired(&mut jobs, self.config.result_ttl);
+
+        if jobs.len() >= self.config.max_jobs {
+            let oldest_finished = jobs
+                .values()
+                .filter(|job| job.finished_at.is_some())
+                .min_by_key(|job| job.created_at)
+                .map(|job| job.id.clone());
+
+            match oldest_finished {
+                Some(id) => {
+                    jobs.remove(&id);
+                }
+
+                None => return Err(Error::Full(self.config.max_jobs)),
+            }
+        }
+
+        let id = new_job_id();
+
+        jobs.insert(
+            id.clone(),
+            Job {
+                id: id.clone(),
+                status: Status::Running(),
+                created_at: Instant::now(),
+                finished_at: None,
+            },
+        );
+
+        Ok(id)
+    }
+
+    // The result is dropped if the job was deleted while running
+    pub fn finish(&self, id: &str, result: T) {
+        if let Some(job) = self.lock().get_mut(id) {
+            job.status = Status::Finished(result);
+            job.finished_at = Some(Instant::now());
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job<T>> {
+        let mut jobs = self.lock();
+        prune_expired(&mut jobs, self.config.result_ttl);
+        jobs.get(id).cloned()
+    }
+
+    pub fn remove(&self, id: &str) -> Option<Job<T>> {
+        self.lock().remove(id)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Job<T>>> {
+        // A poisoned lock only means another thread panicked while holding it, the map is still usable
+        self.jobs.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+fn prune_expired<T>(jobs: &mut HashMap<String, Job<T>>, ttl: Duration) {
+    jobs.retain(|_, job| !job.is_expired(ttl));
+}
+
+fn new_job_id() -> String {
+    let bytes: [u8; 16] = rand::thread_rng().gen();
+
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Full(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Full(max_jobs) => {
+                write!(f, "Too many running jobs (max: {})", max_jobs)
+            }
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Maps language aliases like python or python3.12 to concrete images
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub languages: BTreeMap<String, String>,
+}
+
+impl Config {
+    pub fn image_for(&self, language: &str) -> Option<&String> {
+        self.languages.get(language)
+    }
+}
+
+// The file is a json object with the language name as key and the image as value
+pub fn read_file(path: &Path) -> Result<Config, Error> {
+    let data = fs::read(path).map_err(Error::ReadFile)?;
+    let languages = serde_json::from_slice(&data).map_err(Error::Parse)?;
+
+    Ok(Config { languages })
+}
+
+#[derive(Debug)]
+pub enum Error {
+    ReadFile(io::Error),
+    Parse(serde_json::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ReadFile(err) => {
+                write!(f, "Failed to read languages file: {}", err)
+            }
+
+            Error::Parse(err) => {
+                write!(f, "Failed to parse languages file: {}", err)
+            }
+        }
+    }
+}
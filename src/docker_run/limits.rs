@@ -0,0 +1,330 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::docker_run::run;
+
+// Lower bounds for overrides. Docker treats a memory or nproc value of 0 or less as
+// unlimited and refuses containers with less than 6MB of memory.
+const MIN_EXECUTION_TIME: u64 = 1;
+const MIN_OUTPUT_SIZE: usize = 1;
+const MIN_MEMORY: i64 = 6 * 1024 * 1024;
+const MIN_NPROC: i64 = 1;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub max_execution_time: Duration,
+    pub max_output_size: usize,
+    pub max_memory: i64,
+    pub max_nproc: i64,
+    pub policy: Policy,
+}
+
+#[derive(Clone, Debug)]
+pub enum Policy {
+    Clamp,
+    Reject,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamp" => Ok(Policy::Clamp),
+            "reject" => Ok(Policy::Reject),
+            _ => Err(format!("Unknown policy: {} (expected clamp or reject)", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Overrides {
+    // Seconds
+    pub execution_time: Option<u64>,
+    // Bytes
+    pub output_size: Option<usize>,
+    // Bytes
+    pub memory: Option<i64>,
+    pub nproc: Option<i64>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EffectiveLimits {
+    pub execution_time: u64,
+    pub output_size: usize,
+    pub memory: i64,
+    pub nproc: i64,
+}
+
+impl EffectiveLimits {
+    pub fn new(limits: &run::Limits, container_config: &run::ContainerConfig) -> EffectiveLimits {
+        EffectiveLimits {
+            execution_time: limits.max_execution_time.as_secs(),
+            output_size: limits.max_output_size,
+            memory: container_config.memory,
+            nproc: container_config.ulimit_nproc_hard,
+        }
+    }
+}
+
+pub fn apply(
+    config: &Config,
+    overrides: &Overrides,
+    limits: &mut run::Limits,
+    container_config: &mut run::ContainerConfig,
+) -> Result<(), Error> {
+    // A profile can raise the defaults above the configured maximums, overrides
+    // are then allowed up to the profile's value
+    if let Some(value) = overrides.execution_time {
+        let max = config
+            .max_execution_time
+            .max(limits.max_execution_time)
+            .as_secs();
+        let seconds = bounded(
+            &config.policy,
+            "executionTime",
+            value,
+            MIN_EXECUTION_TIME,
+            max,
+        )?;
+        limits.max_execution_time = Duration::from_secs(seconds);
+    }
+
+    if let Some(value) = overrides.output_size {
+        let max = config.max_output_size.max(limits.max_output_size);
+        limits.max_output_size =
+            bounded(&config.policy, "outputSize", value, MIN_OUTPUT_SIZE, max)?;
+    }
+
+    if let Some(value) = overrides.memory {
+        let max = config.max_memory.max(container_config.memory);
+        container_config.memory = bounded(&config.policy, "memory", value, MIN_MEMORY, max)?;
+    }
+
+    if let Some(value) = overrides.nproc {
+        let max = config.max_nproc.max(container_config.ulimit_nproc_hard);
+        let nproc = bounded(&config.policy, "nproc", value, MIN_NPROC, max)?;
+        container_config.ulimit_nproc_soft = nproc;
+        container_config.ulimit_nproc_hard = nproc;
+    }
+
+    Ok(())
+}
+
+fn bounded<T>(policy: &Policy, name: &'static str, value: T, min: T, max: T) -> Result<T, Error>
+where
+    T: PartialOrd + fmt::Display,
+{
+    if value < min {
+        match policy {
+            Policy::Clamp => Ok(min),
+
+            Policy::Reject => Err(Error::Below {
+                name,
+                value: value.to_string(),
+                min: min.to_string(),
+            }),
+        }
+    } else if value > max {
+        match policy {
+            Policy::Clamp => Ok(max),
+
+            Policy::Reject => Err(Error::Exceeded {
+                name,
+                value: value.to_string(),
+                max: max.to_string(),
+            }),
+        }
+    } else {
+        Ok(value)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Exceeded {
+        name: &'static str,
+        value: String,
+        max: String,
+    },
+    Below {
+        name: &'static str,
+        value: String,
+        min: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Exceeded { name, value, max } => write!(
+                f,
+                "Requested limit «{}» is too high: {} (max: {})",
+                name, value, max
+            ),
+
+            Error::Below { name, value, min } => write!(
+                f,
+                "Requested limit «{}» is too low: {} (min: {})",
+                name, value, min
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(policy: Policy) -> Config {
+        Config {
+            max_execution_time: Duration::from_secs(30),
+            max_output_size: 1000,
+            max_memory: 500_000_000,
+            max_nproc: 100,
+            policy,
+        }
+    }
+
+    fn limits() -> run::Limits {
+        run::Limits {
+            max_execution_time: Duration::from_secs(10),
+            max_output_size: 100,
+        }
+    }
+
+    fn container_config() -> run::ContainerConfig {
+        run::ContainerConfig {
+            hostname: "glot".to_string(),
+            user: "glot".to_string(),
+            memory: 100_000_000,
+            network_disabled: true,
+            ulimit_nofile_soft: 90,
+            ulimit_nofile_hard: 100,
+            ulimit_nproc_soft: 50,
+            ulimit_nproc_hard: 50,
+            cap_add: vec![],
+            cap_drop: vec![],
+            readonly_rootfs: true,
+            tmp_dir: None,
+            work_dir: None,
+            runtime: None,
+            instance_id: "test".to_string(),
+            keep_container: false,
+        }
+    }
+
+    #[test]
+    fn apply_within_bounds() {
+        let mut limits = limits();
+        let mut container_config = container_config();
+        let overrides = Overrides {
+            execution_time: Some(20),
+            output_size: Some(500),
+            memory: Some(200_000_000),
+            nproc: Some(80),
+        };
+
+        apply(
+            &config(Policy::Reject),
+            &overrides,
+            &mut limits,
+            &mut container_config,
+        )
+        .unwrap();
+
+        assert_eq!(limits.max_execution_time, Duration::from_secs(20));
+        assert_eq!(limits.max_output_size, 500);
+        assert_eq!(container_config.memory, 200_000_000);
+        assert_eq!(container_config.ulimit_nproc_soft, 80);
+        assert_eq!(container_config.ulimit_nproc_hard, 80);
+    }
+
+    #[test]
+    fn apply_clamps_to_bounds() {
+        let mut limits = limits();
+        let mut container_config = container_config();
+        let overrides = Overrides {
+            execution_time: Some(60),
+            output_size: Some(0),
+            memory: Some(1),
+            nproc: Some(1000),
+        };
+
+        apply(
+            &config(Policy::Clamp),
+            &overrides,
+            &mut limits,
+            &mut container_config,
+        )
+        .unwrap();
+
+        assert_eq!(limits.max_execution_time, Duration::from_secs(30));
+        assert_eq!(limits.max_output_size, MIN_OUTPUT_SIZE);
+        assert_eq!(container_config.memory, MIN_MEMORY);
+        assert_eq!(container_config.ulimit_nproc_hard, 100);
+    }
+
+    #[test]
+    fn apply_rejects_out_of_bounds() {
+        let overrides = Overrides {
+            memory: Some(1_000_000_000),
+            ..Overrides::default()
+        };
+
+        let result = apply(
+            &config(Policy::Reject),
+            &overrides,
+            &mut limits(),
+            &mut container_config(),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Exceeded { name: "memory", .. })
+        ));
+
+        let overrides = Overrides {
+            execution_time: Some(0),
+            ..Overrides::default()
+        };
+
+        let result = apply(
+            &config(Policy::Reject),
+            &overrides,
+            &mut limits(),
+            &mut container_config(),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Below {
+                name: "executionTime",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn apply_allows_profile_values_above_max() {
+        let mut limits = run::Limits {
+            max_execution_time: Duration::from_secs(60),
+            max_output_size: 100,
+        };
+        let overrides = Overrides {
+            execution_time: Some(45),
+            ..Overrides::default()
+        };
+
+        apply(
+            &config(Policy::Reject),
+            &overrides,
+            &mut limits,
+            &mut container_config(),
+        )
+        .unwrap();
+
+        assert_eq!(limits.max_execution_time, Duration::from_secs(45));
+    }
+}
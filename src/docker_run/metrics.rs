@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::docker_run::queue;
+use crate::docker_run::run;
+
+// Upper bounds in seconds of the duration histogram buckets
+const BUCKETS: [f64; 13] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        for (count, bound) in self.counts.iter_mut().zip(BUCKETS.iter()) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let label_set = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+
+        for (count, bound) in self.counts.iter().zip(BUCKETS.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, bound, count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, separator, self.count
+        );
+
+        let _ = writeln!(out, "{}_sum{} {}", name, label_set, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, label_set, self.count);
+    }
+}
+
+#[derive(Default)]
+struct State {
+    runs: BTreeMap<String, u64>,
+    pool_hits: BTreeMap<String, u64>,
+    pool_misses: BTreeMap<String, u64>,
+    errors: BTreeMap<String, u64>,
+    docker_calls: BTreeMap<&'static str, Histogram>,
+    run_duration: Histogram,
+}
+
+// In-memory counters and histograms, rendered in the prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    state: Mutex<State>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn record_run(&self, image: &str) {
+        *self.lock().runs.entry(image.to_string()).or_default() += 1;
+    }
+
+    pub fn record_pool_claim(&self, image: &str, hit: bool) {
+        let mut state = self.lock();
+
+        let counts = if hit {
+            &mut state.pool_hits
+        } else {
+            &mut state.pool_misses
+        };
+
+        *counts.entry(image.to_string()).or_default() += 1;
+    }
+
+    pub fn record_error(&self, error_code: &str) {
+        *self
+            .lock()
+            .errors
+            .entry(error_code.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn record_run_duration(&self, duration: Duration) {
+        self.lock().run_duration.observe(duration);
+    }
+
+    pub fn record_timings(&self, timings: &run::Timings) {
+        let mut state = self.lock();
+
+        let calls = [
+            ("create", timings.create),
+            ("start", timings.start),
+            // Attaching to the container and streaming until the program is done
+            ("run", timings.run),
+            ("wait", timings.wait),
+            ("remove", timings.remove),
+        ];
+
+        for (call, duration) in calls {
+            state
+                .docker_calls
+                .entry(call)
+                .or_default()
+                .observe(duration);
+        }
+    }
+
+    pub fn render(&self, run_queue: &queue::Queue) -> String {
+        let state = self.lock();
+        let mut out = String::new();
+
+        render_counter(
+            &mut out,
+            "docker_run_runs_total",
+            "Number of runs per image",
+            "image",
+            &state.runs,
+        );
+
+        render_counter(
+            &mut out,
+            "docker_run_pool_hits_total",
+            "Number of runs that used a pooled container",
+            "image",
+            &state.pool_hits,
+        );
+
+        render_counter(
+            &mut out,
+            "docker_run_pool_misses_total",
+            "Number of runs of a pooled image that had to create a container",
+            "image",
+            &state.pool_misses,
+        );
+
+        render_counter(
+            &mut out,
+            "docker_run_errors_total",
+            "Number of errors per error code",
+            "error",
+            &state.errors,
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP docker_run_docker_call_duration_seconds Duration of docker calls of completed runs"
+        );
+        let _ = writeln!(
+            out,
+            "# TYPE docker_run_docker_call_duration_seconds histogram"
+        );
+        for (call, histogram) in &state.docker_calls {
+            histogram.render(
+                &mut out,
+                "docker_run_docker_call_duration_seconds",
+                &format!("call=\"{}\"", call),
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP docker_run_run_duration_seconds Total duration of runs"
+        );
+        let _ = writeln!(out, "# TYPE docker_run_run_duration_seconds histogram");
+        state
+            .run_duration
+            .render(&mut out, "docker_run_run_duration_seconds", "");
+
+        let _ = writeln!(
+            out,
+            "# HELP docker_run_runs_in_flight Number of containers running"
+        );
+        let _ = writeln!(out, "# TYPE docker_run_runs_in_flight gauge");
+        let _ = writeln!(out, "docker_run_runs_in_flight {}", run_queue.running());
+
+        let _ = writeln!(
+            out,
+            "# HELP docker_run_queue_depth Number of runs waiting for a container slot"
+        );
+        let _ = writeln!(out, "# TYPE docker_run_queue_depth gauge");
+        let _ = writeln!(out, "docker_run_queue_depth {}", run_queue.depth());
+
+        out
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+fn render_counter(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    counts: &BTreeMap<String, u64>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+
+    for (value, count) in counts {
+        let _ = writeln!(
+            out,
+            "{}{{{}=\"{}\"}} {}",
+            name,
+            label,
+            escape_label(value),
+            count
+        );
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -1,8 +1,27 @@
+pub mod allowlist;
 pub mod api;
+pub mod auth;
+pub mod cli;
 pub mod config;
+pub mod config_file;
 pub mod debug;
 pub mod docker;
+pub mod doctor;
 pub mod environment;
+pub mod exec;
 pub mod http_extra;
+pub mod images;
+pub mod jobs;
+pub mod languages;
+pub mod limits;
+pub mod metrics;
+pub mod pool;
+pub mod profiles;
+pub mod queue;
+pub mod rate_limit;
+pub mod reaper;
 pub mod run;
+pub mod runtime;
+pub mod tls;
+pub mod tracker;
 pub mod unix_stream;
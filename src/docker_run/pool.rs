@@ -0,0 +1,418 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::docker_run::allowlist;
+use crate::docker_run::docker;
+use crate::docker_run::images;
+use crate::docker_run::profiles;
+use crate::docker_run::run;
+use crate::docker_run::unix_stream;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    // Number of containers to keep ready per image
+    pub sizes: BTreeMap<String, usize>,
+    // Idle containers older than this are replaced, so they are never reaped while pooled
+    pub max_idle: Duration,
+}
+
+struct PooledContainer {
+    id: String,
+    // Containers are created from the image id, so a pull can't change the image of a pooled container
+    image_id: String,
+    created_at: Instant,
+    container_config: run::ContainerConfig,
+}
+
+// Image ids of the pooled images, inspected again after any image is pulled
+#[derive(Default)]
+struct ImageIds {
+    pulled: u64,
+    ids: HashMap<String, String>,
+}
+
+// Replaced when the config is reloaded
+struct ContainerSettings {
+    container_config: run::ContainerConfig,
+    profiles: profiles::Config,
+}
+
+// Keeps created, not yet started, containers ready for runs of the configured images.
+// Containers use the profile matching the image, only runs with that container config
+// can use a pooled container.
+pub struct Pool {
+    config: Config,
+    stream_config: unix_stream::Config,
+    settings: Mutex<ContainerSettings>,
+    image_ids: Mutex<ImageIds>,
+    containers: Mutex<HashMap<String, VecDeque<PooledContainer>>>,
+    claimed: Condvar,
+    draining: AtomicBool,
+}
+
+impl Pool {
+    pub fn new(
+        config: Config,
+        stream_config: unix_stream::Config,
+        container_config: run::ContainerConfig,
+        profiles: profiles::Config,
+    ) -> Pool {
+        Pool {
+            config,
+            stream_config,
+            settings: Mutex::new(ContainerSettings {
+                container_config,
+                profiles,
+            }),
+            image_ids: Mutex::new(ImageIds::default()),
+            containers: Mutex::new(HashMap::new()),
+            claimed: Condvar::new(),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_pooled(&self, image: &str, container_config: &run::ContainerConfig) -> bool {
+        self.config.sizes.contains_key(image) && self.container_config(image) == *container_config
+    }
+
+    // The container config that pooled containers of the image are created with
+    pub fn container_config(&self, image: &str) -> run::ContainerConfig {
+        let settings = self.settings();
+        settings
+            .profiles
+            .container_config(image, &settings.container_config)
+    }
+
+    // Takes a container for the image out of the pool, it's replaced in the background.
+    // Containers of another image id are removed, the image has been pulled again or retagged.
+    // Pulled is the number of pulls so far, see images::PullHistory.
+    pub fn claim(
+        &self,
+        image: &str,
+        container_config: &run::ContainerConfig,
+        pulled: u64,
+    ) -> Option<String> {
+        let image_id = self.image_id(image, pulled)?;
+
+        let (container, stale) = match self.lock().get_mut(image) {
+            Some(containers) => {
+                let (stale, mut current): (VecDeque<_>, VecDeque<_>) = containers
+                    .drain(..)
+                    .partition(|container| container.image_id != *image_id);
+
+                let container = current
+                    .iter()
+                    .position(|container| container.container_config == *container_config)
+                    .and_then(|index| current.remove(index));
+
+                *containers = current;
+                (container, stale)
+            }
+
+            None => (None, VecDeque::new()),
+        };
+
+        for stale_container in stale {
+            log::info!(
+                "Removing pooled container {} of {}, the image has changed",
+                stale_container.id,
+                image
+            );
+            self.remove_container(&stale_container.id);
+        }
+
+        self.claimed.notify_one();
+
+        container.map(|container| container.id)
+    }
+
+    // The cached image id, the image is inspected once per pull instead of on every claim
+    fn image_id(&self, image: &str, pulled: u64) -> Option<String> {
+        {
+            let mut image_ids = self.image_ids();
+
+            if image_ids.pulled != pulled {
+                *image_ids = ImageIds {
+                    pulled,
+                    ids: HashMap::new(),
+                };
+            }
+
+            if let Some(image_id) = image_ids.ids.get(image) {
+                return Some(image_id.clone());
+            }
+        }
+
+        match images::image_id(&self.stream_config, image) {
+            Ok(image_id) => {
+                let mut image_ids = self.image_ids();
+
+                // Another pull may have happened during the inspect
+                if image_ids.pulled == pulled {
+                    image_ids.ids.insert(image.to_string(), image_id.clone());
+                }
+
+                Some(image_id)
+            }
+
+            Err(err) => {
+                log::error!("Failed to inspect pooled image {}: {}", image, err);
+                None
+            }
+        }
+    }
+
+    // Fills the pool in a background thread and keeps it filled
+    pub fn start(self: &Arc<Self>) {
+        if self.config.sizes.is_empty() {
+            return;
+        }
+
+        let pool = Arc::clone(self);
+
+        thread::spawn(move || loop {
+            if pool.draining.load(Ordering::SeqCst) {
+                break;
+            }
+
+            pool.remove_idle();
+            let replenished = pool.replenish();
+
+            let containers = pool.lock();
+
+            // Wakes up on claims and regularly to replace idle containers or retry failed creates
+            if !replenished || pool.is_full(&containers) {
+                let _ = pool
+                    .claimed
+                    .wait_timeout(containers, Duration::from_secs(10));
+            }
+        });
+    }
+
+    // Returns false if a container could not be created
+    fn replenish(&self) -> bool {
+        let mut replenished = true;
+
+        for (image, size) in &self.config.sizes {
+            let missing = size.saturating_sub(self.available(image));
+
+            for _ in 0..missing {
+                if self.draining.load(Ordering::SeqCst) {
+                    return replenished;
+                }
+
+                let image_id = match images::image_id(&self.stream_config, image) {
+                    Ok(image_id) => image_id,
+
+                    Err(err) => {
+                        log::error!("Failed to inspect pooled image {}: {}", image, err);
+                        replenished = false;
+                        break;
+                    }
+                };
+
+                let container_config = self.container_config(image);
+                let docker_container_config =
+                    run::prepare_container_config(image_id.clone(), container_config.clone());
+
+                match run::create_container(&self.stream_config, &docker_container_config) {
+                    // The pool was drained while the container was created
+                    Ok(id) if self.draining.load(Ordering::SeqCst) => {
+                        self.remove_container(&id);
+                        return replenished;
+                    }
+
+                    // The pool was reconfigured while the container was created
+                    Ok(id) if self.container_config(image) != container_config => {
+                        self.remove_container(&id);
+                        return replenished;
+                    }
+
+                    Ok(id) => {
+                        self.lock()
+                            .entry(image.clone())
+                            .or_default()
+                            .push_back(PooledContainer {
+                                id,
+                                image_id,
+                                created_at: Instant::now(),
+                                container_config,
+                            });
+                    }
+
+                    Err(err) => {
+                        log::error!("Failed to create pooled container for {}: {}", image, err);
+                        replenished = false;
+                        break;
+                    }
+                }
+            }
+        }
+
+        replenished
+    }
+
+    fn remove_idle(&self) {
+        let idle: Vec<String> = {
+            let mut containers = self.lock();
+
+            containers
+                .values_mut()
+                .flat_map(|containers| {
+                    let (idle, fresh) = containers.drain(..).partition(|container| {
+                        container.created_at.elapsed() > self.config.max_idle
+                    });
+
+                    *containers = fresh;
+                    idle
+                })
+                .map(|container: PooledContainer| container.id)
+                .collect()
+        };
+
+        for container_id in idle {
+            self.remove_container(&container_id);
+        }
+    }
+
+    // Replaces the pooled containers that were created with the previous config
+    pub fn reconfigure(&self, container_config: run::ContainerConfig, profiles: profiles::Config) {
+        *self.settings() = ContainerSettings {
+            container_config,
+            profiles,
+        };
+
+        // Images could have been retagged since they were inspected
+        self.image_ids().ids.clear();
+
+        let stale: Vec<String> = {
+            let mut containers = self.lock();
+
+            containers
+                .iter_mut()
+                .flat_map(|(image, containers)| {
+                    let current_config = self.container_config(image);
+
+                    let (stale, current) = containers
+                        .drain(..)
+                        .partition(|container| container.container_config != current_config);
+
+                    *containers = current;
+                    stale
+                })
+                .map(|container: PooledContainer| container.id)
+                .collect()
+        };
+
+        for container_id in stale {
+            self.remove_container(&container_id);
+        }
+
+        self.claimed.notify_one();
+    }
+
+    // Removes the pooled containers of the image, so the image can be removed.
+    // The pool creates new containers again once the image is pulled.
+    pub fn discard_image(&self, image: &str) -> usize {
+        let image = allowlist::normalize_image_name(image);
+
+        let container_ids: Vec<String> = self
+            .lock()
+            .iter_mut()
+            .filter(|(pooled_image, _)| allowlist::normalize_image_name(pooled_image) == image)
+            .flat_map(|(_, containers)| containers.drain(..))
+            .map(|container| container.id)
+            .collect();
+
+        for container_id in &container_ids {
+            self.remove_container(container_id);
+        }
+
+        container_ids.len()
+    }
+
+    // Stops replenishing and removes all pooled containers
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        self.claimed.notify_one();
+
+        let container_ids: Vec<String> = self
+            .lock()
+            .drain()
+            .flat_map(|(_, containers)| containers)
+            .map(|container| container.id)
+            .collect();
+
+        for container_id in container_ids {
+            self.remove_container(&container_id);
+        }
+    }
+
+    fn remove_container(&self, container_id: &str) {
+        let result = unix_stream::with_stream(&self.stream_config, Error::UnixStream, |stream| {
+            docker::remove_container(stream, container_id).map_err(Error::RemoveContainer)
+        });
+
+        if let Err(err) = result {
+            log::error!(
+                "Failed to remove pooled container {}: {}",
+                container_id,
+                err
+            );
+        }
+    }
+
+    fn available(&self, image: &str) -> usize {
+        self.lock()
+            .get(image)
+            .map(|containers| containers.len())
+            .unwrap_or(0)
+    }
+
+    fn is_full(&self, containers: &HashMap<String, VecDeque<PooledContainer>>) -> bool {
+        self.config.sizes.iter().all(|(image, size)| {
+            containers
+                .get(image)
+                .map(|containers| containers.len() >= *size)
+                .unwrap_or(false)
+        })
+    }
+
+    fn settings(&self) -> MutexGuard<'_, ContainerSettings> {
+        self.settings.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn image_ids(&self) -> MutexGuard<'_, ImageIds> {
+        self.image_ids.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, VecDeque<PooledContainer>>> {
+        self.containers
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    UnixStream(unix_stream::Error),
+    RemoveContainer(docker::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnixStream(err) => {
+                write!(f, "Unix socket failure: {}", err)
+            }
+
+            Error::RemoveContainer(err) => {
+                write!(f, "Failed to remove container: {}", err)
+            }
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::time::Duration;
+
+use crate::docker_run::allowlist;
+use crate::docker_run::run;
+
+// Profiles are checked in order, the first matching profile is used
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub profiles: Vec<Profile>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    pub name: String,
+    // Image patterns like glot/rust:*, matched like the allowlist patterns
+    pub images: Vec<String>,
+    // Language aliases from the languages file
+    pub languages: Vec<String>,
+    pub overrides: Overrides,
+}
+
+// Fields that are not set keep the global default
+#[derive(Clone, Debug, Default)]
+pub struct Overrides {
+    pub memory: Option<i64>,
+    pub ulimit_nofile_soft: Option<i64>,
+    pub ulimit_nofile_hard: Option<i64>,
+    pub ulimit_nproc_soft: Option<i64>,
+    pub ulimit_nproc_hard: Option<i64>,
+    pub readonly_rootfs: Option<bool>,
+    pub tmp_dir: Option<run::Tmpfs>,
+    pub work_dir: Option<run::Tmpfs>,
+    pub max_execution_time: Option<Duration>,
+    pub max_output_size: Option<usize>,
+}
+
+impl Config {
+    pub fn find(&self, image: &str, language: Option<&str>) -> Option<&Profile> {
+        let image = allowlist::normalize_image_name(image);
+
+        self.profiles.iter().find(|profile| {
+            let language_matches = language
+                .map(|language| profile.languages.iter().any(|alias| alias == language))
+                .unwrap_or(false);
+
+            let image_matches = profile
+                .images
+                .iter()
+                .any(|pattern| allowlist::glob_match(pattern, &image));
+
+            language_matches || image_matches
+        })
+    }
+
+    // The container config of the profile selected by image alone, used for pooled containers
+    pub fn container_config(
+        &self,
+        image: &str,
+        container_config: &run::ContainerConfig,
+    ) -> run::ContainerConfig {
+        let mut container_config = container_config.clone();
+
+        if let Some(profile) = self.find(image, None) {
+            profile.apply_container_config(&mut container_config);
+        }
+
+        container_config
+    }
+}
+
+impl Profile {
+    pub fn apply(&self, limits: &mut run::Limits, container_config: &mut run::ContainerConfig) {
+        self.apply_container_config(container_config);
+
+        if let Some(max_execution_time) = self.overrides.max_execution_time {
+            limits.max_execution_time = max_execution_time;
+        }
+
+        if let Some(max_output_size) = self.overrides.max_output_size {
+            limits.max_output_size = max_output_size;
+        }
+    }
+
+    fn apply_container_config(&self, container_config: &mut run::ContainerConfig) {
+        let overrides = &self.overrides;
+
+        if let Some(memory) = overrides.memory {
+            container_config.memory = memory;
+        }
+
+        if let Some(value) = overrides.ulimit_nofile_soft {
+            container_config.ulimit_nofile_soft = value;
+        }
+
+        if let Some(value) = overrides.ulimit_nofile_hard {
+            container_config.ulimit_nofile_hard = value;
+        }
+
+        if let Some(value) = overrides.ulimit_nproc_soft {
+            container_config.ulimit_nproc_soft = value;
+        }
+
+        if let Some(value) = overrides.ulimit_nproc_hard {
+            container_config.ulimit_nproc_hard = value;
+        }
+
+        if let Some(readonly_rootfs) = overrides.readonly_rootfs {
+            container_config.readonly_rootfs = readonly_rootfs;
+        }
+
+        if let Some(tmp_dir) = &overrides.tmp_dir {
+            container_config.tmp_dir = Some(tmp_dir.clone());
+        }
+
+        if let Some(work_dir) = &overrides.work_dir {
+            container_config.work_dir = Some(work_dir.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(name: &str, images: &[&str], languages: &[&str], memory: i64) -> Profile {
+        Profile {
+            name: name.to_string(),
+            images: images.iter().map(|image| image.to_string()).collect(),
+            languages: languages
+                .iter()
+                .map(|language| language.to_string())
+                .collect(),
+            overrides: Overrides {
+                memory: Some(memory),
+                ..Overrides::default()
+            },
+        }
+    }
+
+    fn config() -> Config {
+        Config {
+            profiles: vec![
+                profile("rust", &["glot/rust:*"], &[], 1000),
+                profile("jvm", &["glot/java:latest"], &["java", "kotlin"], 2000),
+                profile("glot", &["glot/*"], &[], 3000),
+            ],
+        }
+    }
+
+    fn container_config() -> run::ContainerConfig {
+        run::ContainerConfig {
+            hostname: "glot".to_string(),
+            user: "glot".to_string(),
+            memory: 100,
+            network_disabled: true,
+            ulimit_nofile_soft: 90,
+            ulimit_nofile_hard: 100,
+            ulimit_nproc_soft: 90,
+            ulimit_nproc_hard: 100,
+            cap_add: vec![],
+            cap_drop: vec![],
+            readonly_rootfs: true,
+            tmp_dir: None,
+            work_dir: None,
+            runtime: None,
+            instance_id: "test".to_string(),
+            keep_container: false,
+        }
+    }
+
+    fn find_name(config: &Config, image: &str, language: Option<&str>) -> Option<String> {
+        config
+            .find(image, language)
+            .map(|profile| profile.name.clone())
+    }
+
+    #[test]
+    fn find_by_image_pattern() {
+        let config = config();
+
+        assert_eq!(find_name(&config, "glot/rust:1.80", None).unwrap(), "rust");
+        assert_eq!(find_name(&config, "glot/java", None).unwrap(), "jvm");
+        assert_eq!(find_name(&config, "glot/python", None).unwrap(), "glot");
+        assert_eq!(find_name(&config, "glot/x/python", None), None);
+        assert_eq!(find_name(&config, "other/rust:latest", None), None);
+    }
+
+    #[test]
+    fn find_by_language() {
+        let config = config();
+
+        assert_eq!(
+            find_name(&config, "other/kotlin:latest", Some("kotlin")).unwrap(),
+            "jvm"
+        );
+
+        // The first matching profile is used, even if a later one matches the language
+        assert_eq!(
+            find_name(&config, "glot/rust:latest", Some("java")).unwrap(),
+            "rust"
+        );
+    }
+
+    #[test]
+    fn apply_overrides_only_set_fields() {
+        let mut profile = profile("jvm", &[], &[], 2000);
+        profile.overrides.max_execution_time = Some(Duration::from_secs(30));
+
+        let mut limits = run::Limits {
+            max_execution_time: Duration::from_secs(10),
+            max_output_size: 100,
+        };
+        let mut container_config = container_config();
+
+        profile.apply(&mut limits, &mut container_config);
+
+        assert_eq!(limits.max_execution_time, Duration::from_secs(30));
+        assert_eq!(limits.max_output_size, 100);
+        assert_eq!(container_config.memory, 2000);
+        assert_eq!(container_config.ulimit_nproc_hard, 100);
+    }
+
+    #[test]
+    fn container_config_of_image() {
+        let config = config();
+
+        assert_eq!(
+            config
+                .container_config("glot/rust:latest", &container_config())
+                .memory,
+            1000
+        );
+        assert_eq!(
+            config
+                .container_config("other/python:latest", &container_config())
+                .memory,
+            100
+        );
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub max_concurrent_runs: usize,
+    pub max_depth: usize,
+    pub max_wait: Duration,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    // Numbers of the waiting runs in the order they entered the queue
+    waiting: VecDeque<u64>,
+    next_number: u64,
+    // New runs are rejected when the queue is closed, runs already in the queue are kept
+    closed: bool,
+}
+
+// Limits the number of containers running at the same time.
+// Runs wait in the queue for a free slot and get it in the order they entered,
+// the queue rejects new runs when it is full.
+pub struct Queue {
+    config: Config,
+    state: Mutex<State>,
+    slot_released: Condvar,
+}
+
+// Holds a run slot until dropped
+pub struct Ticket<'a> {
+    queue: &'a Queue,
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        let mut state = self.queue.lock();
+        state.running = state.running.saturating_sub(1);
+
+        // Only the first waiting run can take the slot, all are woken up so it's not missed
+        self.queue.slot_released.notify_all();
+    }
+}
+
+impl Queue {
+    pub fn new(config: Config) -> Queue {
+        Queue {
+            config,
+            state: Mutex::new(State::default()),
+            slot_released: Condvar::new(),
+        }
+    }
+
+    // Blocks until a run slot is free, the queue is full or max wait is reached
+    pub fn enter(&self) -> Result<Ticket<'_>, Error> {
+        let mut state = self.lock();
+
+        self.check_capacity_of(&state)?;
+
+        // A free slot is only taken directly when nobody is waiting for one
+        if state.waiting.is_empty() && state.running < self.config.max_concurrent_runs {
+            state.running += 1;
+            return Ok(Ticket { queue: self });
+        }
+
+        let number = state.next_number;
+        state.next_number += 1;
+        state.waiting.push_back(number);
+
+        let deadline = Instant::now() + self.config.max_wait;
+
+        while state.waiting.front() != Some(&number)
+            || state.running >= self.config.max_concurrent_runs
+        {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+
+            if timeout.is_zero() {
+                state.waiting.retain(|waiting| *waiting != number);
+
+                // The next run may be able to take a free slot now
+                self.slot_released.notify_all();
+
+                return Err(Error::MaxWait(self.config.max_wait));
+            }
+
+            state = self
+                .slot_released
+                .wait_timeout(state, timeout)
+                .map(|(state, _)| state)
+                .unwrap_or_else(|err| err.into_inner().0);
+        }
+
+        state.waiting.pop_front();
+        state.running += 1;
+
+        // The next run may be able to take another free slot
+        self.slot_released.notify_all();
+
+        Ok(Ticket { queue: self })
+    }
+
+    // Fails if a run entering the queue now would be rejected without waiting
+    pub fn check_capacity(&self) -> Result<(), Error> {
+        self.check_capacity_of(&self.lock())
+    }
+
+    fn check_capacity_of(&self, state: &State) -> Result<(), Error> {
+        if state.closed {
+            return Err(Error::Closed());
+        }
+
+        let has_free_slot =
+            state.waiting.is_empty() && state.running < self.config.max_concurrent_runs;
+
+        if !has_free_slot && state.waiting.len() >= self.config.max_depth {
+            return Err(Error::Full(self.config.max_depth));
+        }
+
+        Ok(())
+    }
+
+    pub fn close(&self) {
+        self.lock().closed = true;
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+
+    // Number of runs holding a slot
+    pub fn running(&self) -> usize {
+        self.lock().running
+    }
+
+    // Number of slots a run could take without waiting
+    pub fn free_slots(&self) -> usize {
+        let state = self.lock();
+
+        if state.waiting.is_empty() {
+            self.config
+                .max_concurrent_runs
+                .saturating_sub(state.running)
+        } else {
+            0
+        }
+    }
+
+    // Number of runs waiting for a slot
+    pub fn depth(&self) -> usize {
+        self.lock().waiting.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Full(usize),
+    MaxWait(Duration),
+    Closed(),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Full(max_depth) => {
+                write!(f, "Run queue is full (max depth: {})", max_depth)
+            }
+
+            Error::MaxWait(max_wait) => {
+                write!(
+                    f,
+                    "Timed out waiting in the run queue after {} seconds",
+                    max_wait.as_secs()
+                )
+            }
+
+            Error::Closed() => {
+                write!(f, "The server is shutting down")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn queue(max_concurrent_runs: usize, max_depth: usize, max_wait: Duration) -> Queue {
+        Queue::new(Config {
+            max_concurrent_runs,
+            max_depth,
+            max_wait,
+        })
+    }
+
+    fn wait_for_depth(queue: &Queue, depth: usize) {
+        while queue.depth() < depth {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn waiting_runs_get_slots_in_order() {
+        let queue = &queue(1, 10, Duration::from_secs(10));
+        let order = &Mutex::new(Vec::new());
+
+        let ticket = queue.enter().unwrap();
+
+        thread::scope(|scope| {
+            for number in 0..5 {
+                scope.spawn(move || {
+                    let _ticket = queue.enter().unwrap();
+                    order.lock().unwrap().push(number);
+                });
+
+                wait_for_depth(queue, number + 1);
+            }
+
+            drop(ticket);
+        });
+
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(queue.running(), 0);
+        assert_eq!(queue.depth(), 0);
+    }
+
+    #[test]
+    fn enter_rejects_when_full() {
+        let queue = queue(1, 1, Duration::from_secs(10));
+
+        let ticket = queue.enter().unwrap();
+        assert_eq!(queue.free_slots(), 0);
+        assert!(queue.check_capacity().is_ok());
+
+        thread::scope(|scope| {
+            scope.spawn(|| queue.enter().map(|_| ()));
+            wait_for_depth(&queue, 1);
+
+            assert!(matches!(queue.enter(), Err(Error::Full(1))));
+            assert!(matches!(queue.check_capacity(), Err(Error::Full(1))));
+
+            drop(ticket);
+        });
+
+        assert_eq!(queue.free_slots(), 1);
+    }
+
+    #[test]
+    fn enter_times_out() {
+        let queue = queue(1, 1, Duration::from_millis(10));
+
+        let _ticket = queue.enter().unwrap();
+
+        assert!(matches!(queue.enter(), Err(Error::MaxWait(_))));
+        assert_eq!(queue.depth(), 0);
+    }
+
+    #[test]
+    fn enter_rejects_when_closed() {
+        let queue = queue(1, 1, Duration::from_secs(10));
+
+        queue.close();
+
+        assert!(matches!(queue.enter(), Err(Error::Closed())));
+        assert!(matches!(queue.check_capacity(), Err(Error::Closed())));
+    }
+}
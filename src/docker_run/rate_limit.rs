@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// Default limits for all tokens, a token can override these in the tokens file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    pub limits: RateLimit,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimit {
+    pub requests_per_second: Option<f64>,
+    pub burst: Option<u32>,
+    pub max_concurrent_runs: Option<usize>,
+}
+
+impl RateLimit {
+    // A rate that is not positive would never refill the bucket
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(rate) = self.requests_per_second {
+            validate_requests_per_second(rate)?;
+        }
+
+        if let Some(burst) = self.burst {
+            validate_burst(burst)?;
+        }
+
+        if let Some(max) = self.max_concurrent_runs {
+            validate_max_concurrent_runs(max)?;
+        }
+
+        Ok(())
+    }
+
+    // Fields that are not set by the token falls back to the defaults
+    fn or(&self, defaults: &RateLimit) -> RateLimit {
+        RateLimit {
+            requests_per_second: self.requests_per_second.or(defaults.requests_per_second),
+            burst: self.burst.or(defaults.burst),
+            max_concurrent_runs: self.max_concurrent_runs.or(defaults.max_concurrent_runs),
+        }
+    }
+}
+
+pub fn validate_requests_per_second(rate: f64) -> Result<(), String> {
+    err_if_false(
+        rate.is_finite() && rate > 0.0,
+        format!("requestsPerSecond must be greater than 0, got: {}", rate),
+    )
+}
+
+pub fn validate_burst(burst: u32) -> Result<(), String> {
+    err_if_false(
+        burst >= 1,
+        format!("burst must be at least 1, got: {}", burst),
+    )
+}
+
+pub fn validate_max_concurrent_runs(max: usize) -> Result<(), String> {
+    err_if_false(
+        max >= 1,
+        format!("maxConcurrentRuns must be at least 1, got: {}", max),
+    )
+}
+
+// What a request is charged, a batch is charged for each of its payloads
+#[derive(Clone, Copy, Debug)]
+pub struct Cost {
+    // Tokens taken from the bucket
+    pub requests: usize,
+    // Concurrent run slots the request can use, it gets fewer if the token has fewer free slots
+    pub concurrent_runs: usize,
+}
+
+impl Cost {
+    pub fn single_run() -> Cost {
+        Cost {
+            requests: 1,
+            concurrent_runs: 1,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    buckets: HashMap<String, Bucket>,
+    running: HashMap<String, usize>,
+}
+
+pub struct Limiter {
+    config: Config,
+    state: Mutex<State>,
+}
+
+// Holds concurrent run slots for a token until dropped
+pub struct Permit {
+    limiter: Arc<Limiter>,
+    name: String,
+    runs: usize,
+}
+
+impl Permit {
+    // Number of runs the request may run at the same time, at least one
+    pub fn runs(&self) -> usize {
+        self.runs
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.lock();
+
+        if let Some(count) = state.running.get_mut(&self.name) {
+            *count = count.saturating_sub(self.runs);
+        }
+    }
+}
+
+impl Limiter {
+    pub fn new(config: Config) -> Limiter {
+        Limiter {
+            config,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn acquire(
+        self: &Arc<Self>,
+        name: &str,
+        token_limits: Option<&RateLimit>,
+        cost: Cost,
+    ) -> Result<Permit, Error> {
+        let limits = token_limits
+            .map(|limits| limits.or(&self.config.limits))
+            .unwrap_or_else(|| self.config.limits.clone());
+
+        let mut state = self.lock();
+
+        let running = state.running.get(name).copied().unwrap_or(0);
+        let mut runs = cost.concurrent_runs.max(1);
+
+        if let Some(max) = limits.max_concurrent_runs {
+            err_if_false(running < max, Error::TooManyConcurrentRuns(max))?;
+            runs = runs.min(max - running);
+        }
+
+        if let Some(rate) = limits.requests_per_second {
+            let burst = limits
+                .burst
+                .map(f64::from)
+                .unwrap_or_else(|| rate.ceil())
+                .max(1.0);
+            take_from_bucket(&mut state.buckets, name, rate, burst, cost.requests)?;
+        }
+
+        *state.running.entry(name.to_string()).or_insert(0) += runs;
+
+        Ok(Permit {
+            limiter: Arc::clone(self),
+            name: name.to_string(),
+            runs,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+fn take_from_bucket(
+    buckets: &mut HashMap<String, Bucket>,
+    name: &str,
+    rate: f64,
+    burst: f64,
+    requests: usize,
+) -> Result<(), Error> {
+    let requests = requests as f64;
+
+    // The bucket never holds enough tokens, waiting doesn't help
+    err_if_false(
+        requests <= burst,
+        Error::BurstExceeded {
+            requests: requests as usize,
+            burst: burst as usize,
+        },
+    )?;
+
+    let now = Instant::now();
+
+    let bucket = buckets.entry(name.to_string()).or_insert(Bucket {
+        tokens: burst,
+        refilled_at: now,
+    });
+
+    let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+    bucket.refilled_at = now;
+
+    if bucket.tokens >= requests {
+        bucket.tokens -= requests;
+        Ok(())
+    } else {
+        let retry_after = (requests - bucket.tokens) / rate;
+        Err(Error::RateLimited(Duration::from_secs_f64(retry_after)))
+    }
+}
+
+fn err_if_false<E>(value: bool, err: E) -> Result<(), E> {
+    if value {
+        Ok(())
+    } else {
+        Err(err)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    RateLimited(Duration),
+    TooManyConcurrentRuns(usize),
+    BurstExceeded { requests: usize, burst: usize },
+}
+
+impl Error {
+    // Seconds the client should wait before retrying, None if a retry can't succeed
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            Error::RateLimited(duration) => Some(duration.as_secs_f64().ceil().max(1.0) as u64),
+
+            Error::TooManyConcurrentRuns(_) => Some(1),
+
+            Error::BurstExceeded { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::RateLimited(_) => {
+                write!(f, "Rate limit exceeded")
+            }
+
+            Error::TooManyConcurrentRuns(max) => {
+                write!(f, "Too many concurrent runs (max: {})", max)
+            }
+
+            Error::BurstExceeded { requests, burst } => {
+                write!(
+                    f,
+                    "Request is charged for {} runs, more than the rate limit burst of {}",
+                    requests, burst
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(limits: RateLimit) -> Arc<Limiter> {
+        Arc::new(Limiter::new(Config { limits }))
+    }
+
+    fn batch(requests: usize, concurrent_runs: usize) -> Cost {
+        Cost {
+            requests,
+            concurrent_runs,
+        }
+    }
+
+    #[test]
+    fn acquire_takes_from_the_bucket() {
+        let limiter = limiter(RateLimit {
+            requests_per_second: Some(0.001),
+            burst: Some(2),
+            max_concurrent_runs: None,
+        });
+
+        assert!(limiter.acquire("a", None, Cost::single_run()).is_ok());
+        assert!(limiter.acquire("a", None, Cost::single_run()).is_ok());
+        assert!(matches!(
+            limiter.acquire("a", None, Cost::single_run()),
+            Err(Error::RateLimited(_))
+        ));
+
+        // Every token has its own bucket
+        assert!(limiter.acquire("b", None, Cost::single_run()).is_ok());
+    }
+
+    #[test]
+    fn acquire_charges_a_batch_per_request() {
+        let limiter = limiter(RateLimit {
+            requests_per_second: Some(0.001),
+            burst: Some(5),
+            max_concurrent_runs: None,
+        });
+
+        assert!(limiter.acquire("a", None, batch(4, 1)).is_ok());
+        assert!(matches!(
+            limiter.acquire("a", None, batch(2, 1)),
+            Err(Error::RateLimited(_))
+        ));
+        assert!(matches!(
+            limiter.acquire("b", None, batch(6, 1)),
+            Err(Error::BurstExceeded {
+                requests: 6,
+                burst: 5
+            })
+        ));
+    }
+
+    #[test]
+    fn acquire_limits_concurrent_runs() {
+        let limiter = limiter(RateLimit {
+            max_concurrent_runs: Some(3),
+            ..RateLimit::default()
+        });
+
+        let first = limiter.acquire("a", None, Cost::single_run()).unwrap();
+        let batch_permit = limiter.acquire("a", None, batch(10, 4)).unwrap();
+        assert_eq!(batch_permit.runs(), 2);
+
+        assert!(matches!(
+            limiter.acquire("a", None, Cost::single_run()),
+            Err(Error::TooManyConcurrentRuns(3))
+        ));
+
+        drop(batch_permit);
+        drop(first);
+        assert_eq!(limiter.acquire("a", None, batch(10, 4)).unwrap().runs(), 3);
+    }
+
+    #[test]
+    fn token_limits_override_the_defaults() {
+        let limiter = limiter(RateLimit {
+            max_concurrent_runs: Some(1),
+            ..RateLimit::default()
+        });
+        let token_limits = RateLimit {
+            max_concurrent_runs: Some(2),
+            ..RateLimit::default()
+        };
+
+        let _first = limiter
+            .acquire("a", Some(&token_limits), Cost::single_run())
+            .unwrap();
+        assert!(limiter
+            .acquire("a", Some(&token_limits), Cost::single_run())
+            .is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_invalid_limits() {
+        let invalid = [
+            RateLimit {
+                requests_per_second: Some(0.0),
+                ..RateLimit::default()
+            },
+            RateLimit {
+                burst: Some(0),
+                ..RateLimit::default()
+            },
+            RateLimit {
+                max_concurrent_runs: Some(0),
+                ..RateLimit::default()
+            },
+        ];
+
+        for limits in &invalid {
+            assert!(limits.validate().is_err(), "{:?}", limits);
+        }
+    }
+}
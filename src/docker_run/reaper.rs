@@ -0,0 +1,262 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::docker_run::docker;
+use crate::docker_run::unix_stream;
+
+// Set on every container created by docker-run
+pub const INSTANCE_LABEL: &str = "io.glot.docker-run.instance";
+pub const REQUEST_ID_LABEL: &str = "io.glot.docker-run.request-id";
+pub const CREATED_AT_LABEL: &str = "io.glot.docker-run.created-at";
+// Set on containers created while DEBUG_KEEP_CONTAINER is enabled, they are never reaped
+pub const KEEP_LABEL: &str = "io.glot.docker-run.keep";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub max_age: Duration,
+    pub interval: Option<Duration>,
+}
+
+// Labels for a new container, the creation time is stored as unix seconds.
+// The request id is a random id of the container, it's logged with the container id
+// when the container is created and when it's reaped.
+pub fn container_labels(instance_id: &str, keep: bool) -> HashMap<String, String> {
+    let request_id: [u8; 8] = rand::thread_rng().gen();
+
+    let mut labels = HashMap::from([
+        (INSTANCE_LABEL.to_string(), instance_id.to_string()),
+        (REQUEST_ID_LABEL.to_string(), to_hex(&request_id)),
+        (CREATED_AT_LABEL.to_string(), unix_now().to_string()),
+    ]);
+
+    if keep {
+        labels.insert(KEEP_LABEL.to_string(), "true".to_string());
+    }
+
+    labels
+}
+
+pub fn request_id(labels: &HashMap<String, String>) -> &str {
+    label_value(labels, REQUEST_ID_LABEL)
+}
+
+// The hostname, so a restarted process reaps the containers leaked by the previous one.
+// Falls back to a random id if the hostname can't be read.
+pub fn default_instance_id() -> String {
+    let hostname = ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .map(|hostname| hostname.trim().to_string())
+        .find(|hostname| !hostname.is_empty());
+
+    hostname.unwrap_or_else(|| {
+        let bytes: [u8; 8] = rand::thread_rng().gen();
+        let instance_id = to_hex(&bytes);
+
+        log::warn!(
+            "Failed to read the hostname, using the random instance id {}. Containers leaked before a restart are not reaped, set INSTANCE_ID to a stable value",
+            instance_id
+        );
+
+        instance_id
+    })
+}
+
+// Reaps leaked containers once at startup and then at every interval
+pub fn start(stream_config: unix_stream::Config, config: Config, instance_id: String) {
+    thread::spawn(move || loop {
+        match reap(&stream_config, &config, &instance_id) {
+            Ok(count) if count > 0 => {
+                log::warn!("Removed {} leaked containers", count);
+            }
+
+            Ok(_) => {}
+
+            Err(err) => {
+                log::error!("Failed to reap leaked containers: {}", err);
+            }
+        }
+
+        match config.interval {
+            Some(interval) => thread::sleep(interval),
+            None => break,
+        }
+    });
+}
+
+// Force-removes containers created by this instance that are older than max age,
+// except containers kept by DEBUG_KEEP_CONTAINER
+pub fn reap(
+    stream_config: &unix_stream::Config,
+    config: &Config,
+    instance_id: &str,
+) -> Result<usize, Error> {
+    let instance_filter = instance_filter(instance_id);
+
+    let containers = unix_stream::with_stream(stream_config, Error::UnixStream, |stream| {
+        docker::list_containers(stream, &instance_filter).map_err(Error::ListContainers)
+    })?;
+
+    let now = unix_now();
+    let mut removed = 0;
+
+    for container in containers.body() {
+        if !is_reapable(&container.labels, now, config.max_age) {
+            continue;
+        }
+
+        let remove_result = unix_stream::with_stream(stream_config, Error::UnixStream, |stream| {
+            docker::remove_container(stream, &container.id).map_err(Error::RemoveContainer)
+        });
+
+        match remove_result {
+            Ok(_) => {
+                log::info!(
+                    "Removed leaked container {} (instance: {}, request id: {})",
+                    container.id,
+                    label_value(&container.labels, INSTANCE_LABEL),
+                    label_value(&container.labels, REQUEST_ID_LABEL),
+                );
+
+                removed += 1;
+            }
+
+            Err(err) => {
+                log::error!(
+                    "Failed to remove leaked container {}: {}",
+                    container.id,
+                    err
+                );
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+// Label filter for the containers of the instance
+fn instance_filter(instance_id: &str) -> String {
+    format!("{}={}", INSTANCE_LABEL, instance_id)
+}
+
+// Containers kept by DEBUG_KEEP_CONTAINER are never reaped, others when they are older than max age
+fn is_reapable(labels: &HashMap<String, String>, now: u64, max_age: Duration) -> bool {
+    if labels.contains_key(KEEP_LABEL) {
+        return false;
+    }
+
+    let created_at = labels
+        .get(CREATED_AT_LABEL)
+        .and_then(|created_at| created_at.parse::<u64>().ok());
+
+    match created_at {
+        Some(created_at) => now.saturating_sub(created_at) > max_age.as_secs(),
+
+        // The label is set by docker-run, a container without it was tampered with
+        None => true,
+    }
+}
+
+fn label_value<'a>(labels: &'a HashMap<String, String>, key: &str) -> &'a str {
+    labels
+        .get(key)
+        .map(|value| value.as_str())
+        .unwrap_or("unknown")
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[derive(Debug)]
+pub enum Error {
+    UnixStream(unix_stream::Error),
+    ListContainers(docker::Error),
+    RemoveContainer(docker::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnixStream(err) => {
+                write!(f, "Unix socket failure: {}", err)
+            }
+
+            Error::ListContainers(err) => {
+                write!(f, "Failed to list containers: {}", err)
+            }
+
+            Error::RemoveContainer(err) => {
+                write!(f, "Failed to remove container: {}", err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_AGE: Duration = Duration::from_secs(600);
+
+    fn labels_created_at(created_at: u64) -> HashMap<String, String> {
+        HashMap::from([
+            (INSTANCE_LABEL.to_string(), "host".to_string()),
+            (CREATED_AT_LABEL.to_string(), created_at.to_string()),
+        ])
+    }
+
+    #[test]
+    fn container_labels_of_instance() {
+        let labels = container_labels("host", false);
+
+        assert_eq!(labels[INSTANCE_LABEL], "host");
+        assert_eq!(request_id(&labels).len(), 16);
+        assert!(labels[CREATED_AT_LABEL].parse::<u64>().is_ok());
+        assert!(!labels.contains_key(KEEP_LABEL));
+
+        assert_eq!(container_labels("host", true)[KEEP_LABEL], "true");
+        assert_eq!(instance_filter("host"), format!("{}=host", INSTANCE_LABEL));
+    }
+
+    #[test]
+    fn reapable_when_older_than_max_age() {
+        let now = 10_000;
+
+        assert!(is_reapable(&labels_created_at(now - 601), now, MAX_AGE));
+        assert!(!is_reapable(&labels_created_at(now - 600), now, MAX_AGE));
+        assert!(!is_reapable(&labels_created_at(now - 10), now, MAX_AGE));
+
+        // The clock moved backwards since the container was created
+        assert!(!is_reapable(&labels_created_at(now + 10), now, MAX_AGE));
+    }
+
+    #[test]
+    fn reapable_without_created_at() {
+        let mut labels = labels_created_at(0);
+        labels.insert(CREATED_AT_LABEL.to_string(), "invalid".to_string());
+        assert!(is_reapable(&labels, 10_000, MAX_AGE));
+
+        labels.remove(CREATED_AT_LABEL);
+        assert!(is_reapable(&labels, 10_000, MAX_AGE));
+    }
+
+    #[test]
+    fn kept_containers_are_never_reapable() {
+        let mut labels = labels_created_at(0);
+        labels.insert(KEEP_LABEL.to_string(), "true".to_string());
+
+        assert!(!is_reapable(&labels, 10_000, MAX_AGE));
+    }
+}
@@ -2,15 +2,23 @@ use serde::Serialize;
 use serde_json::{Map, Value};
 use std::collections::HashMap;
 use std::fmt;
-use std::net;
-use std::os::unix::net::UnixStream;
+use std::io;
+use std::io::Write;
 use std::str;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::docker_run::debug;
 use crate::docker_run::docker;
+use crate::docker_run::http_extra;
+use crate::docker_run::reaper;
+use crate::docker_run::tracker;
 use crate::docker_run::unix_stream;
 
+// Key of the container state in the run result, the code runner must not return it
+pub const CONTAINER_KEY: &str = "container";
+
+const WAIT_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
 #[derive(Debug)]
 pub struct RunRequest<Payload: Serialize> {
     pub container_config: docker::ContainerConfig,
@@ -24,77 +32,467 @@ pub struct Limits {
     pub max_output_size: usize,
 }
 
+#[derive(Debug)]
+pub struct RunResult<T> {
+    pub output: T,
+    pub container_state: ContainerState,
+    pub timings: Timings,
+}
+
+// Time spent in each docker call of a run
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Timings {
+    #[serde(serialize_with = "serialize_millis")]
+    pub create: Duration,
+    #[serde(serialize_with = "serialize_millis")]
+    pub start: Duration,
+    #[serde(serialize_with = "serialize_millis")]
+    pub run: Duration,
+    #[serde(serialize_with = "serialize_millis")]
+    pub wait: Duration,
+    #[serde(serialize_with = "serialize_millis")]
+    pub remove: Duration,
+    #[serde(serialize_with = "serialize_millis")]
+    pub total: Duration,
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "create: {}ms, start: {}ms, run: {}ms, wait: {}ms, remove: {}ms, total: {}ms",
+            self.create.as_millis(),
+            self.start.as_millis(),
+            self.run.as_millis(),
+            self.wait.as_millis(),
+            self.remove.as_millis(),
+            self.total.as_millis(),
+        )
+    }
+}
+
+fn serialize_millis<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_millis() as u64)
+}
+
+fn measure<T, F: FnOnce() -> T>(duration: &mut Duration, f: F) -> T {
+    let start = Instant::now();
+    let result = f();
+    *duration = start.elapsed();
+    result
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerState {
+    pub exit_code: i64,
+    pub oom_killed: bool,
+    // Container status reported by docker, i.e. exited or dead
+    pub finish_reason: String,
+}
+
 pub fn run<T: Serialize>(
     stream_config: unix_stream::Config,
     run_request: RunRequest<T>,
     debug: debug::Config,
-) -> Result<Map<String, Value>, Error> {
+    tracker: &tracker::Tracker,
+) -> Result<RunResult<Map<String, Value>>, Error> {
+    run_with_output_handler(stream_config, run_request, debug, tracker, |_, _| {})
+}
+
+pub fn run_with_output_handler<T, F>(
+    stream_config: unix_stream::Config,
+    run_request: RunRequest<T>,
+    debug: debug::Config,
+    tracker: &tracker::Tracker,
+    on_output: F,
+) -> Result<RunResult<Map<String, Value>>, Error>
+where
+    T: Serialize,
+    F: FnMut(&docker::StreamType, &[u8]),
+{
+    with_container(
+        &stream_config,
+        &run_request.container_config,
+        &debug,
+        tracker,
+        None,
+        |container_id, timings| {
+            run_with_container(
+                &stream_config,
+                &run_request,
+                container_id,
+                timings,
+                on_output,
+            )
+        },
+    )
+}
+
+// Runs in a container that was created ahead of time with the same container config
+pub fn run_in_created_container<T, F>(
+    stream_config: unix_stream::Config,
+    run_request: &RunRequest<T>,
+    debug: debug::Config,
+    tracker: &tracker::Tracker,
+    container_id: String,
+    on_output: F,
+) -> Result<RunResult<Map<String, Value>>, Error>
+where
+    T: Serialize,
+    F: FnMut(&docker::StreamType, &[u8]),
+{
+    with_container(
+        &stream_config,
+        &run_request.container_config,
+        &debug,
+        tracker,
+        Some(container_id),
+        |container_id, timings| {
+            run_with_container(
+                &stream_config,
+                run_request,
+                container_id,
+                timings,
+                on_output,
+            )
+        },
+    )
+}
+
+pub fn create_container(
+    stream_config: &unix_stream::Config,
+    container_config: &docker::ContainerConfig,
+) -> Result<String, Error> {
     let container_response =
-        unix_stream::with_stream(&stream_config, Error::UnixStream, |stream| {
-            docker::create_container(stream, &run_request.container_config)
-                .map_err(Error::CreateContainer)
+        unix_stream::with_stream(stream_config, Error::UnixStream, |stream| {
+            docker::create_container(stream, container_config).map_err(Error::CreateContainer)
         })?;
 
-    let container_id = &container_response.body().id;
+    let container_id = container_response.body().id.clone();
 
-    let result = run_with_container(&stream_config, run_request, container_id);
+    log::info!(
+        "Created container {} of {} (request id: {})",
+        container_id,
+        container_config.image,
+        reaper::request_id(&container_config.labels)
+    );
 
-    if !debug.keep_container {
-        let _ = unix_stream::with_stream(&stream_config, Error::UnixStream, |stream| {
-            match docker::remove_container(stream, container_id) {
-                Ok(_) => {}
+    Ok(container_id)
+}
 
-                Err(err) => {
-                    log::error!("Failed to remove container: {}", err);
-                }
-            }
+#[derive(Debug)]
+pub struct RawRunRequest {
+    pub container_config: docker::ContainerConfig,
+    pub stdin: Vec<u8>,
+    pub limits: Limits,
+}
+
+#[derive(Debug)]
+pub struct RawOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+pub fn run_raw(
+    stream_config: unix_stream::Config,
+    run_request: RawRunRequest,
+    debug: debug::Config,
+    tracker: &tracker::Tracker,
+) -> Result<RunResult<RawOutput>, Error> {
+    with_container(
+        &stream_config,
+        &run_request.container_config,
+        &debug,
+        tracker,
+        None,
+        |container_id, timings| {
+            measure(&mut timings.start, || {
+                start_container(&stream_config, container_id)
+            })?;
+
+            let run_config = run_stream_config(&stream_config, &run_request.limits);
+
+            let output = measure(&mut timings.run, || {
+                unix_stream::with_stream(&run_config, Error::UnixStream, |stream| {
+                    communicate(
+                        stream,
+                        container_id,
+                        &run_request.limits,
+                        |stream| {
+                            stream
+                                .write_all(&run_request.stdin)
+                                .map_err(Error::WriteStdin)
+                        },
+                        |_, _| {},
+                    )
+                })
+            })?;
+
+            let wait_config = wait_stream_config(&stream_config, &run_request.limits, timings.run);
+
+            let container_state = measure(&mut timings.wait, || {
+                wait_for_exit(&stream_config, &wait_config, container_id)
+            })?;
+
+            let raw_output = RawOutput {
+                stdout: output.stdout,
+                stderr: output.stderr,
+            };
+
+            Ok((raw_output, container_state))
+        },
+    )
+}
+
+// Creates a container, unless one was created ahead of time,
+// calls f with the container id and removes the container again
+fn with_container<F, T>(
+    stream_config: &unix_stream::Config,
+    container_config: &docker::ContainerConfig,
+    debug: &debug::Config,
+    tracker: &tracker::Tracker,
+    created_container_id: Option<String>,
+    f: F,
+) -> Result<RunResult<T>, Error>
+where
+    F: FnOnce(&str, &mut Timings) -> Result<(T, ContainerState), Error>,
+{
+    let run_start = Instant::now();
+    let mut timings = Timings::default();
+
+    // Containers are tracked as soon as they exist, so a shutdown can't miss them
+    let create_result = match created_container_id {
+        Some(container_id) if tracker.track(&container_id) => Ok(container_id),
+
+        Some(container_id) => {
+            remove_container(stream_config, &container_id);
+            Err(Error::ShuttingDown())
+        }
+
+        None => measure(&mut timings.create, || {
+            tracker
+                .create(|| create_container(stream_config, container_config))
+                .unwrap_or(Err(Error::ShuttingDown()))
+        }),
+    };
 
-            Ok(())
+    let container_id = match create_result {
+        Ok(container_id) => container_id,
+
+        Err(err) => {
+            timings.total = run_start.elapsed();
+            log::info!(
+                "Run of {} failed, timings: {}",
+                container_config.image,
+                timings
+            );
+            return Err(err);
+        }
+    };
+
+    let container_id = &container_id;
+
+    let result = f(container_id, &mut timings);
+
+    if !debug.keep_container {
+        measure(&mut timings.remove, || {
+            remove_container(stream_config, container_id);
         });
     }
 
-    result
+    tracker.untrack(container_id);
+    timings.total = run_start.elapsed();
+
+    log::info!(
+        "Run of {} in container {} {}, timings: {}",
+        container_config.image,
+        container_id,
+        if result.is_ok() {
+            "completed"
+        } else {
+            "failed"
+        },
+        timings
+    );
+
+    result.map(|(output, container_state)| RunResult {
+        output,
+        container_state,
+        timings,
+    })
 }
 
-pub fn run_with_container<T: Serialize>(
+fn remove_container(stream_config: &unix_stream::Config, container_id: &str) {
+    let _ = unix_stream::with_stream(stream_config, Error::UnixStream, |stream| {
+        match docker::remove_container(stream, container_id) {
+            Ok(_) => {}
+
+            Err(err) => {
+                log::error!("Failed to remove container: {}", err);
+            }
+        }
+
+        Ok(())
+    });
+}
+
+pub fn run_with_container<T, F>(
     stream_config: &unix_stream::Config,
-    run_request: RunRequest<T>,
+    run_request: &RunRequest<T>,
     container_id: &str,
-) -> Result<Map<String, Value>, Error> {
+    timings: &mut Timings,
+    on_output: F,
+) -> Result<(Map<String, Value>, ContainerState), Error>
+where
+    T: Serialize,
+    F: FnMut(&docker::StreamType, &[u8]),
+{
+    measure(&mut timings.start, || {
+        start_container(stream_config, container_id)
+    })?;
+
+    let run_config = run_stream_config(stream_config, &run_request.limits);
+
+    let output = measure(&mut timings.run, || {
+        unix_stream::with_stream(&run_config, Error::UnixStream, |stream| {
+            run_code(stream, container_id, run_request, on_output)
+        })
+    })?;
+
+    let wait_config = wait_stream_config(stream_config, &run_request.limits, timings.run);
+
+    let container_state = measure(&mut timings.wait, || {
+        wait_for_exit(stream_config, &wait_config, container_id)
+    })?;
+
+    Ok((decode_output(output)?, container_state))
+}
+
+// Waits for the container to exit and inspects how it finished.
+// A container killed by the oom killer often leaves partial or no output behind,
+// so this is reported as an error before the output is looked at.
+fn wait_for_exit(
+    stream_config: &unix_stream::Config,
+    wait_config: &unix_stream::Config,
+    container_id: &str,
+) -> Result<ContainerState, Error> {
+    // The output stream is closed when the container exits, so this usually returns immediately.
+    // A program that closed its output and keeps running hits the execution time limit here.
+    let wait_response = unix_stream::with_stream(wait_config, Error::UnixStream, |stream| {
+        docker::wait_container(stream, container_id).map_err(|err| {
+            if is_read_timeout(&err) {
+                Error::ReadStream(docker::StreamError::MaxExecutionTime())
+            } else {
+                Error::WaitContainer(err)
+            }
+        })
+    })?;
+
+    let inspect_response = unix_stream::with_stream(stream_config, Error::UnixStream, |stream| {
+        docker::inspect_container(stream, container_id).map_err(Error::InspectContainer)
+    })?;
+
+    let exit_code = wait_response.body().status_code;
+    let state = &inspect_response.body().state;
+
+    log::debug!(
+        "Container {} finished with status {} and exit code {} (oom killed: {})",
+        container_id,
+        state.status,
+        exit_code,
+        state.oom_killed
+    );
+
+    err_if_false(!state.oom_killed, Error::OutOfMemory(exit_code))?;
+
+    Ok(ContainerState {
+        exit_code,
+        oom_killed: state.oom_killed,
+        finish_reason: state.status.clone(),
+    })
+}
+
+fn start_container(stream_config: &unix_stream::Config, container_id: &str) -> Result<(), Error> {
     unix_stream::with_stream(stream_config, Error::UnixStream, |stream| {
         docker::start_container(stream, container_id).map_err(Error::StartContainer)
     })?;
 
-    let run_config = unix_stream::Config {
-        read_timeout: run_request.limits.max_execution_time,
+    Ok(())
+}
+
+// The read timeout of the stream that is attached to the container acts as the execution time limit
+fn run_stream_config(stream_config: &unix_stream::Config, limits: &Limits) -> unix_stream::Config {
+    unix_stream::Config {
+        read_timeout: limits.max_execution_time,
         ..stream_config.clone()
+    }
+}
+
+// Waiting for the exit gets the rest of the execution time that the output stream didn't use,
+// plus a grace period for docker to report the exit of a container that just closed its output
+fn wait_stream_config(
+    stream_config: &unix_stream::Config,
+    limits: &Limits,
+    run_duration: Duration,
+) -> unix_stream::Config {
+    let remaining = limits.max_execution_time.saturating_sub(run_duration);
+
+    unix_stream::Config {
+        read_timeout: remaining + WAIT_GRACE_PERIOD,
+        ..stream_config.clone()
+    }
+}
+
+fn is_read_timeout(err: &docker::Error) -> bool {
+    // Docker sends the response head right away and the body when the container exits
+    let io_err = match err {
+        docker::Error::SendRequest(http_extra::Error::ReadResponse(err)) => err,
+        docker::Error::SendRequest(http_extra::Error::ReadBody(err)) => err,
+
+        docker::Error::SendRequest(http_extra::Error::ReadChunkedBody(
+            http_extra::ReadChunkError::ReadChunkLength(err),
+        )) => err,
+
+        docker::Error::SendRequest(http_extra::Error::ReadChunkedBody(
+            http_extra::ReadChunkError::ReadChunk(err),
+        )) => err,
+
+        _ => return false,
     };
 
-    unix_stream::with_stream(&run_config, Error::UnixStream, |stream| {
-        run_code(stream, container_id, &run_request)
-    })
+    matches!(
+        io_err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
 }
 
-pub fn run_code<Payload>(
-    mut stream: &UnixStream,
+pub fn run_code<Payload, F>(
+    stream: &mut unix_stream::Stream,
     container_id: &str,
     run_request: &RunRequest<Payload>,
-) -> Result<Map<String, Value>, Error>
+    on_output: F,
+) -> Result<docker::StreamOutput, Error>
 where
     Payload: Serialize,
+    F: FnMut(&docker::StreamType, &[u8]),
 {
-    docker::attach_container(&mut stream, container_id).map_err(Error::AttachContainer)?;
-
-    // Send payload
-    serde_json::to_writer(&mut stream, &run_request.payload).map_err(Error::SerializePayload)?;
-
-    // Shutdown write stream which will trigger an EOF on the reader
-    let _ = stream.shutdown(net::Shutdown::Write);
-
-    // Read response
-    let output = docker::read_stream(stream, run_request.limits.max_output_size)
-        .map_err(Error::ReadStream)?;
+    communicate(
+        stream,
+        container_id,
+        &run_request.limits,
+        |stream| {
+            serde_json::to_writer(stream, &run_request.payload).map_err(Error::SerializePayload)
+        },
+        on_output,
+    )
+}
 
+fn decode_output(output: docker::StreamOutput) -> Result<Map<String, Value>, Error> {
     // Return error if we recieved stdin or stderr data from the stream
     err_if_false(
         output.stdin.is_empty(),
@@ -103,10 +501,42 @@ where
     err_if_false(output.stderr.is_empty(), Error::StreamStderr(output.stderr))?;
 
     // Decode stdout data to dict
-    decode_dict(&output.stdout).map_err(Error::StreamStdoutDecode)
+    let output = decode_dict(&output.stdout).map_err(Error::StreamStdoutDecode)?;
+
+    // The container state is added to the output under this key
+    err_if_false(
+        !output.contains_key(CONTAINER_KEY),
+        Error::StreamStdoutReservedKey(CONTAINER_KEY.to_string()),
+    )?;
+
+    Ok(output)
 }
 
-#[derive(Debug, Clone)]
+// Attaches to the container, writes the input to stdin and reads the output until the container exits
+fn communicate<W, F>(
+    stream: &mut unix_stream::Stream,
+    container_id: &str,
+    limits: &Limits,
+    write_input: W,
+    on_output: F,
+) -> Result<docker::StreamOutput, Error>
+where
+    W: FnOnce(&mut unix_stream::Stream) -> Result<(), Error>,
+    F: FnMut(&docker::StreamType, &[u8]),
+{
+    docker::attach_container(&mut *stream, container_id).map_err(Error::AttachContainer)?;
+
+    // Send input
+    write_input(stream)?;
+
+    // Shutdown write stream which will trigger an EOF on the reader
+    stream.shutdown_write();
+
+    // Read response
+    docker::read_stream(stream, limits.max_output_size, on_output).map_err(Error::ReadStream)
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ContainerConfig {
     pub hostname: String,
     pub user: String,
@@ -121,9 +551,14 @@ pub struct ContainerConfig {
     pub readonly_rootfs: bool,
     pub tmp_dir: Option<Tmpfs>,
     pub work_dir: Option<Tmpfs>,
+    // The default runtime of the docker daemon is used if not set
+    pub runtime: Option<String>,
+    pub instance_id: String,
+    // Containers are labeled so the reaper doesn't remove them
+    pub keep_container: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Tmpfs {
     pub path: String,
     pub options: String,
@@ -155,7 +590,9 @@ pub fn prepare_container_config(
         open_stdin: true,
         stdin_once: true,
         image: image_name,
+        cmd: None,
         network_disabled: config.network_disabled,
+        labels: reaper::container_labels(&config.instance_id, config.keep_container),
         host_config: docker::HostConfig {
             memory: config.memory,
             privileged: false,
@@ -175,6 +612,7 @@ pub fn prepare_container_config(
             ],
             readonly_rootfs: config.readonly_rootfs,
             tmpfs,
+            runtime: config.runtime,
         },
     }
 }
@@ -185,11 +623,17 @@ pub enum Error {
     CreateContainer(docker::Error),
     StartContainer(docker::Error),
     AttachContainer(docker::Error),
+    WaitContainer(docker::Error),
+    InspectContainer(docker::Error),
+    OutOfMemory(i64),
+    WriteStdin(io::Error),
     SerializePayload(serde_json::Error),
     ReadStream(docker::StreamError),
     StreamStdinUnexpected(Vec<u8>),
     StreamStderr(Vec<u8>),
     StreamStdoutDecode(serde_json::Error),
+    StreamStdoutReservedKey(String),
+    ShuttingDown(),
 }
 
 impl fmt::Display for Error {
@@ -211,6 +655,26 @@ impl fmt::Display for Error {
                 write!(f, "Failed to attach to container: {}", err)
             }
 
+            Error::WaitContainer(err) => {
+                write!(f, "Failed to wait for container: {}", err)
+            }
+
+            Error::InspectContainer(err) => {
+                write!(f, "Failed to inspect container: {}", err)
+            }
+
+            Error::OutOfMemory(exit_code) => {
+                write!(
+                    f,
+                    "Container was killed after running out of memory (exit code: {})",
+                    exit_code
+                )
+            }
+
+            Error::WriteStdin(err) => {
+                write!(f, "Failed to send stdin to stream: {}", err)
+            }
+
             Error::SerializePayload(err) => {
                 write!(f, "Failed to send payload to stream: {}", err)
             }
@@ -238,6 +702,18 @@ impl fmt::Display for Error {
                     err
                 )
             }
+
+            Error::StreamStdoutReservedKey(key) => {
+                write!(
+                    f,
+                    "Code runner returned the key '{}' which is reserved for the container state",
+                    key
+                )
+            }
+
+            Error::ShuttingDown() => {
+                write!(f, "The server is shutting down")
+            }
         }
     }
 }
@@ -0,0 +1,38 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use crate::docker_run::config;
+use crate::docker_run::images;
+use crate::docker_run::metrics;
+use crate::docker_run::pool;
+use crate::docker_run::queue;
+use crate::docker_run::tracker;
+
+// State shared by all runs of the server
+pub struct Runtime {
+    pub queue: queue::Queue,
+    pub metrics: metrics::Metrics,
+    pub pool: Arc<pool::Pool>,
+    pub tracker: tracker::Tracker,
+    pub pull_history: images::PullHistory,
+    // Set when the images to pre-pull at startup are pulled
+    pub images_ready: AtomicBool,
+}
+
+impl Runtime {
+    pub fn new(config: &config::Config) -> Runtime {
+        Runtime {
+            queue: queue::Queue::new(config.queue.clone()),
+            metrics: metrics::Metrics::new(),
+            pool: Arc::new(pool::Pool::new(
+                config.pool.clone(),
+                config.unix_socket.clone(),
+                config.container.clone(),
+                config.profiles.clone(),
+            )),
+            tracker: tracker::Tracker::new(),
+            pull_history: images::PullHistory::new(),
+            images_ready: AtomicBool::new(config.images.pre_pull.is_empty()),
+        }
+    }
+}
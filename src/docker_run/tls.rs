@@ -0,0 +1,144 @@
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub type TlsStream = rustls::StreamOwned<rustls::ClientConnection, TcpStream>;
+
+// The docker daemon is verified against the ca and docker-run authenticates with the client certificate
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub ca_file: PathBuf,
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    client_config: Arc<rustls::ClientConfig>,
+}
+
+// The client config is loaded from the files, so the files identify the config
+impl PartialEq for Config {
+    fn eq(&self, other: &Self) -> bool {
+        self.ca_file == other.ca_file
+            && self.cert_file == other.cert_file
+            && self.key_file == other.key_file
+    }
+}
+
+pub fn load(ca_file: PathBuf, cert_file: PathBuf, key_file: PathBuf) -> Result<Config, Error> {
+    let mut roots = rustls::RootCertStore::empty();
+
+    for cert in read_certs(&ca_file)? {
+        roots.add(cert).map_err(Error::InvalidCa)?;
+    }
+
+    let certs = read_certs(&cert_file)?;
+    let key = read_key(&key_file)?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let client_config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(Error::Tls)?
+        .with_root_certificates(roots)
+        .with_client_auth_cert(certs, key)
+        .map_err(Error::Tls)?;
+
+    Ok(Config {
+        ca_file,
+        cert_file,
+        key_file,
+        client_config: Arc::new(client_config),
+    })
+}
+
+// The handshake happens on the first read or write
+pub fn connect(config: &Config, host: &str, stream: TcpStream) -> Result<TlsStream, Error> {
+    let server_name =
+        ServerName::try_from(host.to_string()).map_err(|_| Error::InvalidHost(host.to_string()))?;
+
+    let connection = rustls::ClientConnection::new(Arc::clone(&config.client_config), server_name)
+        .map_err(Error::Tls)?;
+
+    Ok(rustls::StreamOwned::new(connection, stream))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let data = read_file(path)?;
+
+    let certs = rustls_pemfile::certs(&mut data.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| Error::ReadFile(path.to_path_buf(), err))?;
+
+    if certs.is_empty() {
+        Err(Error::NoCertificate(path.to_path_buf()))
+    } else {
+        Ok(certs)
+    }
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>, Error> {
+    let data = read_file(path)?;
+
+    rustls_pemfile::private_key(&mut data.as_slice())
+        .map_err(|err| Error::ReadFile(path.to_path_buf(), err))?
+        .ok_or_else(|| Error::NoPrivateKey(path.to_path_buf()))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    fs::read(path).map_err(|err| Error::ReadFile(path.to_path_buf(), err))
+}
+
+#[derive(Debug)]
+pub enum Error {
+    ReadFile(PathBuf, io::Error),
+    NoCertificate(PathBuf),
+    NoPrivateKey(PathBuf),
+    InvalidCa(rustls::Error),
+    InvalidHost(String),
+    Tls(rustls::Error),
+}
+
+impl Error {
+    // The file the error is about, if any
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::ReadFile(path, _) => Some(path),
+            Error::NoCertificate(path) => Some(path),
+            Error::NoPrivateKey(path) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ReadFile(path, err) => {
+                write!(f, "Failed to read {}: {}", path.display(), err)
+            }
+
+            Error::NoCertificate(path) => {
+                write!(f, "No certificate found in {}", path.display())
+            }
+
+            Error::NoPrivateKey(path) => {
+                write!(f, "No private key found in {}", path.display())
+            }
+
+            Error::InvalidCa(err) => {
+                write!(f, "Invalid ca certificate: {}", err)
+            }
+
+            Error::InvalidHost(host) => {
+                write!(f, "Invalid host name for tls: {}", host)
+            }
+
+            Error::Tls(err) => {
+                write!(f, "Tls failure: {}", err)
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::docker_run::docker;
+use crate::docker_run::unix_stream;
+
+#[derive(Default)]
+struct State {
+    containers: HashSet<String>,
+    // Creates that have been started but not returned yet
+    creating: usize,
+    // Set by remove_all, containers of runs that are still in-flight are removed by the runs
+    closed: bool,
+}
+
+// Keeps track of the containers of in-flight runs, so they can be removed on shutdown
+#[derive(Default)]
+pub struct Tracker {
+    state: Mutex<State>,
+    create_finished: Condvar,
+}
+
+impl Tracker {
+    pub fn new() -> Tracker {
+        Tracker::default()
+    }
+
+    // Creates a container and tracks it. The create is registered before it's started,
+    // so remove_all waits for it. Returns None if remove_all has been called.
+    pub fn create<E, F>(&self, create: F) -> Option<Result<String, E>>
+    where
+        F: FnOnce() -> Result<String, E>,
+    {
+        {
+            let mut state = self.lock();
+
+            if state.closed {
+                return None;
+            }
+
+            state.creating += 1;
+        }
+
+        let result = create();
+
+        let mut state = self.lock();
+        state.creating -= 1;
+
+        if let Ok(container_id) = &result {
+            state.containers.insert(container_id.clone());
+        }
+
+        self.create_finished.notify_all();
+
+        Some(result)
+    }
+
+    // Tracks a container that was created ahead of time.
+    // Returns false if remove_all has been called, the container is then not tracked.
+    pub fn track(&self, container_id: &str) -> bool {
+        let mut state = self.lock();
+
+        if !state.closed {
+            state.containers.insert(container_id.to_string());
+        }
+
+        !state.closed
+    }
+
+    pub fn untrack(&self, container_id: &str) {
+        self.lock().containers.remove(container_id);
+    }
+
+    // Stops tracking new containers, waits up to max wait for creates in progress
+    // and force-removes all tracked containers, returns the number of removed containers
+    pub fn remove_all(&self, stream_config: &unix_stream::Config, max_wait: Duration) -> usize {
+        let deadline = Instant::now() + max_wait;
+        let mut state = self.lock();
+        state.closed = true;
+
+        while state.creating > 0 {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+
+            if timeout.is_zero() {
+                log::warn!(
+                    "Gave up waiting for {} container creates, the containers may be left behind",
+                    state.creating
+                );
+                break;
+            }
+
+            state = self
+                .create_finished
+                .wait_timeout(state, timeout)
+                .map(|(state, _)| state)
+                .unwrap_or_else(|err| err.into_inner().0);
+        }
+
+        let container_ids: Vec<String> = state.containers.drain().collect();
+        drop(state);
+
+        let mut removed = 0;
+
+        for container_id in container_ids {
+            let result = unix_stream::with_stream(stream_config, Error::UnixStream, |stream| {
+                docker::remove_container(stream, &container_id).map_err(Error::RemoveContainer)
+            });
+
+            match result {
+                Ok(_) => {
+                    removed += 1;
+                }
+
+                Err(err) => {
+                    log::error!("Failed to remove container {}: {}", container_id, err);
+                }
+            }
+        }
+
+        removed
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    UnixStream(unix_stream::Error),
+    RemoveContainer(docker::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnixStream(err) => {
+                write!(f, "Unix socket failure: {}", err)
+            }
+
+            Error::RemoveContainer(err) => {
+                write!(f, "Failed to remove container: {}", err)
+            }
+        }
+    }
+}
@@ -1,63 +1,231 @@
 use std::fmt;
 use std::io;
-use std::net::Shutdown;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
 use std::os::unix::net::UnixStream;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::time::Duration;
 
-#[derive(Debug, Clone)]
+use crate::docker_run::tls;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Config {
-    pub path: PathBuf,
+    pub address: Address,
     pub read_timeout: Duration,
     pub write_timeout: Duration,
 }
 
+// Where the docker daemon listens
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+    Unix(PathBuf),
+    Tcp {
+        host: String,
+        port: u16,
+        tls: Option<tls::Config>,
+    },
+}
+
+// Addresses are written like DOCKER_HOST, i.e. unix:///var/run/docker.sock or tcp://10.0.0.2:2376.
+// Tls is configured separately.
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix://") {
+            return Ok(Address::Unix(PathBuf::from(path)));
+        }
+
+        let host_port = s
+            .strip_prefix("tcp://")
+            .ok_or_else(|| format!("Expected unix:// or tcp:// address, got: {}", s))?;
+
+        let (host, port) = host_port
+            .trim_end_matches('/')
+            .rsplit_once(':')
+            .ok_or_else(|| format!("Missing port in address: {}", s))?;
+
+        let port = port
+            .parse()
+            .map_err(|err| format!("Invalid port in address {}: {}", s, err))?;
+
+        // Ipv6 addresses are written in brackets, i.e. tcp://[::1]:2376
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        if host.is_empty() {
+            return Err(format!("Missing host in address: {}", s));
+        }
+
+        Ok(Address::Tcp {
+            host: host.to_string(),
+            port,
+            tls: None,
+        })
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Unix(path) => write!(f, "unix://{}", path.display()),
+            Address::Tcp { host, port, .. } => write!(f, "tcp://{}:{}", host, port),
+        }
+    }
+}
+
+// Connection to the docker daemon
+pub enum Stream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+    Tls(Box<tls::TlsStream>),
+}
+
+impl Stream {
+    // Signals end of input to the daemon, the response can still be read
+    pub fn shutdown_write(&mut self) {
+        match self {
+            Stream::Unix(stream) => {
+                let _ = stream.shutdown(Shutdown::Write);
+            }
+
+            Stream::Tcp(stream) => {
+                let _ = stream.shutdown(Shutdown::Write);
+            }
+
+            Stream::Tls(stream) => {
+                stream.conn.send_close_notify();
+                let _ = stream.flush();
+                let _ = stream.sock.shutdown(Shutdown::Write);
+            }
+        }
+    }
+
+    fn shutdown(&mut self) {
+        match self {
+            Stream::Unix(stream) => {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+
+            Stream::Tcp(stream) => {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+
+            Stream::Tls(stream) => {
+                stream.conn.send_close_notify();
+                let _ = stream.flush();
+                let _ = stream.sock.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(stream) => stream.read(buf),
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Unix(stream) => stream.flush(),
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
 pub fn with_stream<F, T, E, ErrorTagger>(
     config: &Config,
     to_error: ErrorTagger,
     f: F,
 ) -> Result<T, E>
 where
-    F: FnOnce(&mut UnixStream) -> Result<T, E>,
+    F: FnOnce(&mut Stream) -> Result<T, E>,
     ErrorTagger: Copy,
     ErrorTagger: FnOnce(Error) -> E,
 {
-    let mut stream = UnixStream::connect(&config.path)
-        .map_err(Error::Connect)
-        .map_err(to_error)?;
-
-    stream
-        .set_read_timeout(Some(config.read_timeout))
-        .map_err(Error::SetStreamTimeout)
-        .map_err(to_error)?;
-
-    stream
-        .set_write_timeout(Some(config.write_timeout))
-        .map_err(Error::SetStreamTimeout)
-        .map_err(to_error)?;
+    let mut stream = connect(config).map_err(to_error)?;
 
     let result = f(&mut stream)?;
 
-    let _ = stream.shutdown(Shutdown::Both);
+    stream.shutdown();
 
     Ok(result)
 }
 
+fn connect(config: &Config) -> Result<Stream, Error> {
+    match &config.address {
+        Address::Unix(path) => {
+            let stream = UnixStream::connect(path).map_err(Error::Connect)?;
+
+            stream
+                .set_read_timeout(Some(config.read_timeout))
+                .map_err(Error::SetStreamTimeout)?;
+
+            stream
+                .set_write_timeout(Some(config.write_timeout))
+                .map_err(Error::SetStreamTimeout)?;
+
+            Ok(Stream::Unix(stream))
+        }
+
+        Address::Tcp { host, port, tls } => {
+            let stream = TcpStream::connect((host.as_str(), *port)).map_err(Error::Connect)?;
+
+            stream
+                .set_read_timeout(Some(config.read_timeout))
+                .map_err(Error::SetStreamTimeout)?;
+
+            stream
+                .set_write_timeout(Some(config.write_timeout))
+                .map_err(Error::SetStreamTimeout)?;
+
+            match tls {
+                Some(tls_config) => {
+                    let stream = tls::connect(tls_config, host, stream).map_err(Error::Tls)?;
+                    Ok(Stream::Tls(Box::new(stream)))
+                }
+
+                None => Ok(Stream::Tcp(stream)),
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     Connect(io::Error),
     SetStreamTimeout(io::Error),
+    Tls(tls::Error),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::Connect(err) => {
-                write!(f, "Failed to connect to docker unix socket: {}", err)
+                write!(f, "Failed to connect to docker: {}", err)
             }
 
             Error::SetStreamTimeout(err) => {
-                write!(f, "Failed set timeout on unix socket: {}", err)
+                write!(f, "Failed set timeout on docker connection: {}", err)
+            }
+
+            Error::Tls(err) => {
+                write!(f, "Failed to set up tls connection to docker: {}", err)
             }
         }
     }
@@ -1,48 +1,349 @@
 mod docker_run;
 
+use std::convert::Infallible;
+use std::path::PathBuf;
 use std::process;
-use std::time::Duration;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use actix_web::http::header;
 use actix_web::http::header::ContentType;
 use actix_web::http::StatusCode;
 use actix_web::App;
 use actix_web::HttpRequest;
 use actix_web::HttpResponse;
 use actix_web::HttpServer;
-use actix_web::{get, post, web};
+use actix_web::{delete, get, post, web};
+use tokio::sync::mpsc;
 
+use docker_run::allowlist;
 use docker_run::api;
+use docker_run::auth;
+use docker_run::cli;
 use docker_run::config;
+use docker_run::config_file;
 use docker_run::debug;
+use docker_run::doctor;
 use docker_run::environment;
+use docker_run::exec;
+use docker_run::images;
+use docker_run::jobs;
+use docker_run::languages;
+use docker_run::limits;
+use docker_run::pool;
+use docker_run::profiles;
+use docker_run::queue;
+use docker_run::rate_limit;
+use docker_run::reaper;
 use docker_run::run;
+use docker_run::runtime;
+use docker_run::tls;
 use docker_run::unix_stream;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
 
-    let env = environment::get_environment();
+    let args = prepare_args();
+    let env = prepare_environment(&args);
+
+    match args.command {
+        cli::Command::Serve => (),
+        cli::Command::CheckConfig => process::exit(check_config(&env)),
+        cli::Command::Doctor => process::exit(run_doctor(&env)),
+        cli::Command::Exec(exec_args) => process::exit(run_exec(&env, exec_args)),
+
+        cli::Command::Help => {
+            println!("{}", cli::USAGE);
+            process::exit(0)
+        }
+    }
+
     let config = prepare_config(&env);
 
     let listen_addr = config.server.listen_addr.clone();
     let listen_port = config.server.listen_port;
     let worker_threads = config.server.worker_threads;
+    let job_store = web::Data::new(api::jobs::Store::new(config.jobs.clone()));
+    let access_tokens = web::Data::new(prepare_access_tokens(&config));
+    let rate_limiter = web::Data::new(rate_limit::Limiter::new(config.rate_limit.clone()));
+    let runtime = web::Data::new(runtime::Runtime::new(&config));
+    let metrics_server = prepare_metrics_server(&config, &runtime)?;
+
+    log::info!("Instance id: {}", config.container.instance_id);
+    reaper::start(
+        config.unix_socket.clone(),
+        config.reaper.clone(),
+        config.container.instance_id.clone(),
+    );
+    runtime.pool.start();
+    start_pre_pull(&config, &runtime);
+
+    let shared_config = web::Data::new(config::Shared::new(config.clone()));
+    let config_for_shutdown = config.clone();
+    let runtime_for_shutdown = runtime.clone();
+    let config_for_reload = shared_config.clone().into_inner();
+    let runtime_for_reload = runtime.clone().into_inner();
 
     log::info!("Listening on {}:{}", listen_addr, listen_port,);
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(config.clone()))
+            .app_data(shared_config.clone())
+            .app_data(job_store.clone())
+            .app_data(access_tokens.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(runtime.clone())
             .service(index_api)
+            .service(liveness_api)
+            .service(readiness_api)
             .service(version_api)
+            .service(languages_api)
             .service(run_api)
+            .service(run_stream_api)
+            .service(run_batch_api)
+            .service(submit_job_api)
+            .service(get_job_api)
+            .service(delete_job_api)
+            .service(metrics_api)
+            .service(list_images_api)
+            .service(pull_image_api)
+            .service(remove_image_api)
     })
     .workers(worker_threads)
     .client_request_timeout(Duration::from_secs(60))
+    .disable_signals()
     .bind((listen_addr, listen_port))?
-    .run()
+    .run();
+
+    let mut server_handles = vec![server.handle()];
+    server_handles.extend(metrics_server.as_ref().map(|server| server.handle()));
+
+    actix_web::rt::spawn(shutdown_on_signal(
+        config_for_shutdown,
+        runtime_for_shutdown,
+        server_handles,
+    ));
+
+    actix_web::rt::spawn(reload_on_signal(
+        args.config_path,
+        config_for_reload,
+        runtime_for_reload,
+    ));
+
+    match metrics_server {
+        Some(metrics_server) => futures_util::future::try_join(server, metrics_server)
+            .await
+            .map(|_| ()),
+
+        None => server.await,
+    }
+}
+
+// Stops accepting new runs, waits for in-flight runs for the grace period,
+// removes the containers of runs that are still in-flight and stops the servers
+async fn shutdown_on_signal(
+    config: config::Config,
+    runtime: web::Data<runtime::Runtime>,
+    server_handles: Vec<actix_web::dev::ServerHandle>,
+) {
+    wait_for_shutdown_signal().await;
+
+    let grace_period = config.server.shutdown_grace_period;
+
+    log::info!(
+        "Shutting down, waiting up to {} seconds for in-flight runs",
+        grace_period.as_secs()
+    );
+
+    runtime.queue.close();
+
+    let pool_runtime = runtime.clone().into_inner();
+    let _ = web::block(move || pool_runtime.pool.drain()).await;
+
+    let deadline = Instant::now() + grace_period;
+
+    while runtime.queue.running() + runtime.queue.depth() + runtime.pull_history.in_flight() > 0
+        && Instant::now() < deadline
+    {
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let tracker_runtime = runtime.clone().into_inner();
+    let stream_config = config.unix_socket.clone();
+
+    // A create takes at most the socket timeouts
+    let max_create_wait = stream_config.read_timeout + stream_config.write_timeout;
+
+    match web::block(move || {
+        tracker_runtime
+            .tracker
+            .remove_all(&stream_config, max_create_wait)
+    })
     .await
+    {
+        Ok(0) => {}
+
+        Ok(removed) => {
+            log::warn!("Removed {} containers of unfinished runs", removed);
+        }
+
+        Err(err) => {
+            log::error!("Failed to remove containers of unfinished runs: {}", err);
+        }
+    }
+
+    for server_handle in server_handles {
+        server_handle.stop(true).await;
+    }
+}
+
+// Re-reads the config on SIGHUP, the config is kept if the new one has errors
+async fn reload_on_signal(
+    config_path: Option<PathBuf>,
+    shared_config: Arc<config::Shared>,
+    runtime: Arc<runtime::Runtime>,
+) {
+    use actix_web::rt::signal;
+
+    let mut hangup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+
+        Err(err) => {
+            log::error!("Failed to listen for SIGHUP: {}", err);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        let config_path = config_path.clone();
+        let shared_config = shared_config.clone();
+        let runtime = runtime.clone();
+
+        let result =
+            web::block(move || reload_config(&config_path, &shared_config, &runtime)).await;
+
+        if let Err(err) = result {
+            log::error!("Failed to reload config: {}", err);
+        }
+    }
+}
+
+fn reload_config(
+    config_path: &Option<PathBuf>,
+    shared_config: &config::Shared,
+    runtime: &runtime::Runtime,
+) {
+    log::info!("Reloading config");
+
+    let env = match read_environment(config_path) {
+        Ok(env) => env,
+
+        Err(err) => {
+            log::error!(
+                "Failed to reload config, keeping the current config: {}",
+                err
+            );
+            return;
+        }
+    };
+
+    let mut config = match build_config(&env) {
+        Ok(config) => config,
+
+        Err(errors) => {
+            for err in errors {
+                log::error!(
+                    "Failed to reload config, keeping the current config: {}",
+                    err
+                );
+            }
+
+            return;
+        }
+    };
+
+    let current_config = shared_config.load();
+
+    for section in config.keep_static_sections(&current_config) {
+        log::warn!(
+            "Ignoring changes to the {} config, they require a restart",
+            section
+        );
+    }
+
+    runtime
+        .pool
+        .reconfigure(config.container.clone(), config.profiles.clone());
+
+    shared_config.store(config);
+
+    log::info!("Reloaded config");
+}
+
+async fn wait_for_shutdown_signal() {
+    use actix_web::rt::signal;
+
+    let mut terminate = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+
+        Err(err) => {
+            log::error!("Failed to listen for SIGTERM: {}", err);
+            let _ = signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    futures_util::future::select(Box::pin(terminate.recv()), Box::pin(signal::ctrl_c())).await;
+}
+
+// Pulls the configured images in the background, the service is ready when they are pulled
+fn start_pre_pull(config: &config::Config, runtime: &web::Data<runtime::Runtime>) {
+    if config.images.pre_pull.is_empty() {
+        return;
+    }
+
+    let config = config.clone();
+    let runtime = runtime.clone().into_inner();
+
+    // Failed pulls are retried until all images are pulled
+    std::thread::spawn(move || {
+        while !images::pre_pull(&config.images, &runtime.pull_history, &config.unix_socket) {
+            std::thread::sleep(Duration::from_secs(30));
+        }
+
+        log::info!("Pre-pulled {} images", config.images.pre_pull.len());
+        runtime.images_ready.store(true, Ordering::SeqCst);
+    });
+}
+
+// Starts a separate listener serving metrics without access token, if configured
+fn prepare_metrics_server(
+    config: &config::Config,
+    runtime: &web::Data<runtime::Runtime>,
+) -> std::io::Result<Option<actix_web::dev::Server>> {
+    let listen_port = match config.metrics.listen_port {
+        Some(listen_port) => listen_port,
+        None => return Ok(None),
+    };
+
+    let listen_addr = config.metrics.listen_addr.clone();
+    let runtime = runtime.clone();
+
+    log::info!("Serving metrics on {}:{}", listen_addr, listen_port);
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(runtime.clone())
+            .route("/metrics", web::get().to(public_metrics_api))
+    })
+    .workers(1)
+    .disable_signals()
+    .bind((listen_addr, listen_port))?
+    .run();
+
+    Ok(Some(server))
 }
 
 #[get("/")]
@@ -52,14 +353,55 @@ async fn index_api() -> HttpResponse {
         .unwrap_or_else(prepare_error_response)
 }
 
+#[get("/healthz")]
+async fn liveness_api() -> HttpResponse {
+    api::health::handle_liveness()
+        .map(prepare_success_response)
+        .unwrap_or_else(prepare_error_response)
+}
+
+#[get("/readyz")]
+async fn readiness_api(
+    config: web::Data<config::Shared>,
+    runtime: web::Data<runtime::Runtime>,
+) -> HttpResponse {
+    let config = config.load();
+    let runtime = runtime.into_inner();
+
+    web::block(move || api::health::handle_readiness(&config, &runtime))
+        .await
+        .unwrap_or_else(|err| Err(blocking_error(err)))
+        .map(prepare_success_response)
+        .unwrap_or_else(prepare_error_response)
+}
+
 #[get("/version")]
-async fn version_api(req: HttpRequest, config: web::Data<config::Config>) -> HttpResponse {
-    if !has_valid_access_token(&req, &config) {
-        prepare_error_response(api::authorization_error())
-    } else {
-        api::version::handle(&config)
+async fn version_api(
+    req: HttpRequest,
+    config: web::Data<config::Shared>,
+    access_tokens: web::Data<auth::Store>,
+) -> HttpResponse {
+    match authorize(&req, &access_tokens, auth::Scope::Version) {
+        Err(err) => prepare_error_response(err),
+
+        Ok(_) => api::version::handle(&config.load())
+            .map(prepare_success_response)
+            .unwrap_or_else(prepare_error_response),
+    }
+}
+
+#[get("/languages")]
+async fn languages_api(
+    req: HttpRequest,
+    config: web::Data<config::Shared>,
+    access_tokens: web::Data<auth::Store>,
+) -> HttpResponse {
+    match authorize(&req, &access_tokens, auth::Scope::Run) {
+        Err(err) => prepare_error_response(err),
+
+        Ok(_) => api::languages::handle(&config.load())
             .map(prepare_success_response)
-            .unwrap_or_else(prepare_error_response)
+            .unwrap_or_else(prepare_error_response),
     }
 }
 
@@ -67,17 +409,305 @@ async fn version_api(req: HttpRequest, config: web::Data<config::Config>) -> Htt
 async fn run_api(
     req: HttpRequest,
     req_body: web::Json<api::run::RequestBody>,
-    config: web::Data<config::Config>,
+    config: web::Data<config::Shared>,
+    access_tokens: web::Data<auth::Store>,
+    rate_limiter: web::Data<rate_limit::Limiter>,
+    runtime: web::Data<runtime::Runtime>,
 ) -> HttpResponse {
-    if !has_valid_access_token(&req, &config) {
-        prepare_error_response(api::authorization_error())
-    } else {
-        api::run::handle(&config, req_body.into_inner())
+    let _permit = match authorize_run(
+        &req,
+        &access_tokens,
+        &rate_limiter,
+        &runtime,
+        rate_limit::Cost::single_run(),
+    ) {
+        Ok(permit) => permit,
+        Err(response) => return response,
+    };
+
+    let queue_depth = runtime.queue.depth();
+
+    let config = config.load();
+    let run_runtime = runtime.clone().into_inner();
+    let include_timings = wants_timings(&req);
+
+    let response = web::block(move || {
+        api::run::handle(
+            &config,
+            &run_runtime,
+            req_body.into_inner(),
+            include_timings,
+        )
+    })
+    .await
+    .unwrap_or_else(|err| Err(blocking_error(err)))
+    .map(prepare_success_response)
+    .unwrap_or_else(prepare_error_response);
+
+    with_queue_depth(response, queue_depth)
+}
+
+#[post("/run/stream")]
+async fn run_stream_api(
+    req: HttpRequest,
+    req_body: web::Json<api::run::RequestBody>,
+    config: web::Data<config::Shared>,
+    access_tokens: web::Data<auth::Store>,
+    rate_limiter: web::Data<rate_limit::Limiter>,
+    runtime: web::Data<runtime::Runtime>,
+) -> HttpResponse {
+    let permit = match authorize_run(
+        &req,
+        &access_tokens,
+        &rate_limiter,
+        &runtime,
+        rate_limit::Cost::single_run(),
+    ) {
+        Ok(permit) => permit,
+        Err(response) => return response,
+    };
+
+    let queue_depth = runtime.queue.depth();
+
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let config = config.load();
+    let run_runtime = runtime.clone().into_inner();
+    let include_timings = wants_timings(&req);
+
+    actix_web::rt::task::spawn_blocking(move || {
+        api::run_stream::handle(
+            &config,
+            &run_runtime,
+            req_body.into_inner(),
+            include_timings,
+            |event| {
+                // The receiver is gone if the client disconnected, the run is completed anyway
+                let _ = sender.send(event.to_bytes());
+            },
+        );
+
+        drop(permit);
+    });
+
+    let events = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver
+            .recv()
+            .await
+            .map(|event| (Ok::<_, Infallible>(web::Bytes::from(event)), receiver))
+    });
+
+    let response = HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events);
+
+    with_queue_depth(response, queue_depth)
+}
+
+#[post("/run/batch")]
+async fn run_batch_api(
+    req: HttpRequest,
+    req_body: web::Json<api::run_batch::RequestBody>,
+    config: web::Data<config::Shared>,
+    access_tokens: web::Data<auth::Store>,
+    rate_limiter: web::Data<rate_limit::Limiter>,
+    runtime: web::Data<runtime::Runtime>,
+) -> HttpResponse {
+    let config = config.load();
+    let payload_count = req_body.payloads.len().min(config.batch.max_size);
+
+    // Every payload is charged, a batch can use a concurrent run slot per parallel payload
+    let cost = rate_limit::Cost {
+        requests: payload_count.max(1),
+        concurrent_runs: payload_count.min(config.batch.max_parallelism),
+    };
+
+    let permit = match authorize_run(&req, &access_tokens, &rate_limiter, &runtime, cost) {
+        Ok(permit) => permit,
+        Err(response) => return response,
+    };
+
+    let queue_depth = runtime.queue.depth();
+
+    let run_runtime = runtime.clone().into_inner();
+    let max_parallelism = permit.runs();
+
+    let response = web::block(move || {
+        api::run_batch::handle(
+            &config,
+            &run_runtime,
+            req_body.into_inner(),
+            max_parallelism,
+        )
+    })
+    .await
+    .unwrap_or_else(|err| Err(blocking_error(err)))
+    .map(prepare_success_response)
+    .unwrap_or_else(prepare_error_response);
+
+    with_queue_depth(response, queue_depth)
+}
+
+#[post("/jobs")]
+async fn submit_job_api(
+    req: HttpRequest,
+    req_body: web::Json<api::run::RequestBody>,
+    config: web::Data<config::Shared>,
+    access_tokens: web::Data<auth::Store>,
+    rate_limiter: web::Data<rate_limit::Limiter>,
+    job_store: web::Data<api::jobs::Store>,
+    runtime: web::Data<runtime::Runtime>,
+) -> HttpResponse {
+    let permit = match authorize_run(
+        &req,
+        &access_tokens,
+        &rate_limiter,
+        &runtime,
+        rate_limit::Cost::single_run(),
+    ) {
+        Ok(permit) => permit,
+        Err(response) => return response,
+    };
+
+    let queue_depth = runtime.queue.depth();
+
+    let response = api::jobs::handle_submit(
+        config.load().as_ref().clone(),
+        job_store.into_inner(),
+        runtime.clone().into_inner(),
+        req_body.into_inner(),
+        wants_timings(&req),
+        permit,
+    )
+    .map(prepare_success_response)
+    .unwrap_or_else(prepare_error_response);
+
+    with_queue_depth(response, queue_depth)
+}
+
+#[get("/jobs/{id}")]
+async fn get_job_api(
+    req: HttpRequest,
+    path: web::Path<String>,
+    access_tokens: web::Data<auth::Store>,
+    job_store: web::Data<api::jobs::Store>,
+) -> HttpResponse {
+    match authorize(&req, &access_tokens, auth::Scope::Run) {
+        Err(err) => prepare_error_response(err),
+
+        Ok(_) => api::jobs::handle_get(&job_store, &path)
+            .map(prepare_success_response)
+            .unwrap_or_else(prepare_error_response),
+    }
+}
+
+#[delete("/jobs/{id}")]
+async fn delete_job_api(
+    req: HttpRequest,
+    path: web::Path<String>,
+    access_tokens: web::Data<auth::Store>,
+    job_store: web::Data<api::jobs::Store>,
+) -> HttpResponse {
+    match authorize(&req, &access_tokens, auth::Scope::Run) {
+        Err(err) => prepare_error_response(err),
+
+        Ok(_) => api::jobs::handle_delete(&job_store, &path)
             .map(prepare_success_response)
-            .unwrap_or_else(prepare_error_response)
+            .unwrap_or_else(prepare_error_response),
+    }
+}
+
+#[get("/images")]
+async fn list_images_api(
+    req: HttpRequest,
+    config: web::Data<config::Shared>,
+    access_tokens: web::Data<auth::Store>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&req, &access_tokens, auth::Scope::Admin) {
+        return prepare_error_response(err);
+    }
+
+    let config = config.load();
+
+    web::block(move || api::images::handle_list(&config))
+        .await
+        .unwrap_or_else(|err| Err(blocking_error(err)))
+        .map(prepare_success_response)
+        .unwrap_or_else(prepare_error_response)
+}
+
+#[post("/images/pull")]
+async fn pull_image_api(
+    req: HttpRequest,
+    req_body: web::Json<api::images::PullRequestBody>,
+    config: web::Data<config::Shared>,
+    access_tokens: web::Data<auth::Store>,
+    runtime: web::Data<runtime::Runtime>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&req, &access_tokens, auth::Scope::Admin) {
+        return prepare_error_response(err);
+    }
+
+    let config = config.load();
+
+    web::block(move || {
+        api::images::handle_pull(&config, &runtime.pull_history, req_body.into_inner())
+    })
+    .await
+    .unwrap_or_else(|err| Err(blocking_error(err)))
+    .map(prepare_success_response)
+    .unwrap_or_else(prepare_error_response)
+}
+
+// Image names contain slashes, so the rest of the path is the image
+#[delete("/images/{image:.*}")]
+async fn remove_image_api(
+    req: HttpRequest,
+    path: web::Path<String>,
+    config: web::Data<config::Shared>,
+    access_tokens: web::Data<auth::Store>,
+    runtime: web::Data<runtime::Runtime>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&req, &access_tokens, auth::Scope::Admin) {
+        return prepare_error_response(err);
+    }
+
+    let config = config.load();
+    let image = path.into_inner();
+
+    web::block(move || {
+        api::images::handle_remove(&config, &runtime.pool, &runtime.pull_history, &image)
+    })
+    .await
+    .unwrap_or_else(|err| Err(blocking_error(err)))
+    .map(prepare_success_response)
+    .unwrap_or_else(prepare_error_response)
+}
+
+#[get("/metrics")]
+async fn metrics_api(
+    req: HttpRequest,
+    access_tokens: web::Data<auth::Store>,
+    runtime: web::Data<runtime::Runtime>,
+) -> HttpResponse {
+    match authorize(&req, &access_tokens, auth::Scope::Metrics) {
+        Err(err) => prepare_error_response(err),
+
+        Ok(_) => prepare_metrics_response(&runtime),
     }
 }
 
+// Served without access token on the separate metrics listener
+async fn public_metrics_api(runtime: web::Data<runtime::Runtime>) -> HttpResponse {
+    prepare_metrics_response(&runtime)
+}
+
+fn prepare_metrics_response(runtime: &runtime::Runtime) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(runtime.metrics.render(&runtime.queue))
+}
+
 fn prepare_success_response(data: api::SuccessResponse) -> HttpResponse {
     let status_code = StatusCode::from_u16(data.status_code).unwrap_or(StatusCode::OK);
 
@@ -98,114 +728,614 @@ fn prepare_error_response(data: api::ErrorResponse) -> HttpResponse {
         .body(body)
 }
 
-fn has_valid_access_token(request: &HttpRequest, config: &config::Config) -> bool {
+fn authorize(
+    request: &HttpRequest,
+    access_tokens: &auth::Store,
+    scope: auth::Scope,
+) -> Result<auth::Token, api::ErrorResponse> {
     let access_token = request
         .headers()
         .get("X-Access-Token")
-        .map(|token| token.to_str().unwrap_or(""));
+        // A header that is not valid utf-8 can't match any token, it's treated as missing
+        .and_then(|token| token.to_str().ok());
+
+    match access_tokens.authorize(access_token, scope) {
+        Ok(token) => {
+            log::info!(
+                "{} {} (token: {})",
+                request.method(),
+                request.path(),
+                token.name
+            );
+
+            Ok(token)
+        }
+
+        Err(err) => {
+            log::warn!("{} {} rejected: {}", request.method(), request.path(), err);
+
+            Err(api::authorization_error(err))
+        }
+    }
+}
+
+// Authorizes a request that runs code and takes a concurrent run slot for the token
+fn authorize_run(
+    request: &HttpRequest,
+    access_tokens: &auth::Store,
+    rate_limiter: &web::Data<rate_limit::Limiter>,
+    runtime: &runtime::Runtime,
+    cost: rate_limit::Cost,
+) -> Result<rate_limit::Permit, HttpResponse> {
+    if runtime.queue.is_closed() {
+        let err = api::run::handle_queue_error(queue::Error::Closed());
+        runtime.metrics.record_error(&err.body.error);
+        return Err(prepare_error_response(err));
+    }
+
+    let token = authorize(request, access_tokens, auth::Scope::Run).map_err(|err| {
+        runtime.metrics.record_error(&err.body.error);
+        prepare_error_response(err)
+    })?;
+
+    rate_limiter
+        .clone()
+        .into_inner()
+        .acquire(&token.name, token.rate_limit.as_ref(), cost)
+        .map_err(|err| {
+            log::warn!("Rate limited token {}: {}", token.name, err);
 
-    match access_token {
-        Some(token) => token == config.api.access_token,
-        None => false,
+            let error_response = api::rate_limit_error(&err);
+            runtime.metrics.record_error(&error_response.body.error);
+
+            let mut response = prepare_error_response(error_response);
+
+            if let Some(retry_after) = err.retry_after() {
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, header::HeaderValue::from(retry_after));
+            }
+
+            response
+        })
+}
+
+fn blocking_error(err: actix_web::error::BlockingError) -> api::ErrorResponse {
+    api::ErrorResponse {
+        status_code: 500,
+        body: api::ErrorBody {
+            error: "server.blocking".to_string(),
+            message: err.to_string(),
+        },
+    }
+}
+
+// Reports the number of runs that were waiting for a container slot when the request came in
+fn with_queue_depth(mut response: HttpResponse, queue_depth: usize) -> HttpResponse {
+    response.headers_mut().insert(
+        header::HeaderName::from_static("x-queue-depth"),
+        header::HeaderValue::from(queue_depth),
+    );
+
+    response
+}
+
+// Timings are included in the run result if requested by header or query parameter
+fn wants_timings(request: &HttpRequest) -> bool {
+    let header_value = request
+        .headers()
+        .get("X-Include-Timings")
+        .map(|value| value.to_str().unwrap_or(""));
+
+    let query_value = request
+        .query_string()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("timings="));
+
+    [header_value, query_value]
+        .iter()
+        .flatten()
+        .any(|value| *value == "true" || *value == "1")
+}
+
+fn prepare_access_tokens(config: &config::Config) -> auth::Store {
+    match auth::Store::new(config.auth.clone()) {
+        Ok(store) => store,
+
+        Err(err) => {
+            log::error!("Failed to load access tokens: {}", err);
+            process::exit(1)
+        }
+    }
+}
+
+// Environment variables override the values from the config file
+fn prepare_args() -> cli::Args {
+    match cli::parse(std::env::args().skip(1).collect()) {
+        Ok(args) => args,
+
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, cli::USAGE);
+            process::exit(2)
+        }
+    }
+}
+
+fn prepare_environment(args: &cli::Args) -> environment::Environment {
+    match read_environment(&args.config_path) {
+        Ok(env) => env,
+
+        Err(err) => {
+            log::error!("{}", err);
+            process::exit(1)
+        }
+    }
+}
+
+// Reads the environment variables and the config file, if any
+fn read_environment(config_path: &Option<PathBuf>) -> Result<environment::Environment, String> {
+    let env = environment::get_environment();
+
+    match config_file_path(config_path, &env) {
+        Some(path) => match config_file::read(&path) {
+            Ok(file_env) => {
+                log::info!("Read config file {}", path.display());
+                Ok(env.merged_over(file_env))
+            }
+
+            Err(err) => Err(format!(
+                "Failed to load config file {}: {}",
+                path.display(),
+                err
+            )),
+        },
+
+        None => Ok(env),
+    }
+}
+
+// The config file is given with --config <path> or DOCKER_RUN_CONFIG
+fn config_file_path(
+    config_path: &Option<PathBuf>,
+    env: &environment::Environment,
+) -> Option<PathBuf> {
+    config_path
+        .clone()
+        .or_else(|| env.get("DOCKER_RUN_CONFIG").map(PathBuf::from))
+}
+
+// Returns the exit code, non-zero if the config has errors
+fn check_config(env: &environment::Environment) -> i32 {
+    let mut errors = build_config(env).err().unwrap_or_default();
+
+    // The tokens file is otherwise only read when the server starts
+    errors.extend(check_access_tokens_file(env));
+
+    errors.extend(env.unread_file_keys());
+
+    if errors.is_empty() {
+        println!("Config is valid");
+        return 0;
+    }
+
+    for err in &errors {
+        println!("{}", err);
+    }
+
+    println!("Found {} config error(s)", errors.len());
+    1
+}
+
+fn check_access_tokens_file(env: &environment::Environment) -> Option<environment::Error> {
+    let tokens_file = environment::lookup_optional(env, "API_ACCESS_TOKENS_FILE")
+        .ok()
+        .flatten()?;
+
+    let auth_config = auth::Config {
+        access_token: None,
+        tokens_file: Some(tokens_file),
+    };
+
+    auth::Store::new(auth_config)
+        .err()
+        .map(|err| environment::parse_error(env, "API_ACCESS_TOKENS_FILE", err.to_string()))
+}
+
+// Prints the run result, or the error and returns an exit code for its category
+fn run_exec(env: &environment::Environment, exec_args: cli::ExecArgs) -> i32 {
+    let config = prepare_config(env);
+
+    match exec::run(&config, exec_args) {
+        Ok(result) => {
+            let json = serde_json::to_string_pretty(&result).unwrap_or_default();
+            println!("{}", json);
+            0
+        }
+
+        Err(err) => {
+            eprintln!("{}", err);
+            err.exit_code()
+        }
+    }
+}
+
+// Returns the exit code, non-zero if the config has errors or a check failed
+fn run_doctor(env: &environment::Environment) -> i32 {
+    let config = match build_config(env) {
+        Ok(config) => config,
+
+        Err(errors) => {
+            for err in &errors {
+                println!("{}", err);
+            }
+
+            println!("Fix the config errors first, see docker-run check-config");
+            return 1;
+        }
+    };
+
+    let checks = doctor::run(&config);
+
+    for check in &checks {
+        println!("{}", check);
+    }
+
+    let failed = checks
+        .iter()
+        .any(|check| check.status == doctor::Status::Failed);
+
+    if failed {
+        1
+    } else {
+        0
     }
 }
 
 fn prepare_config(env: &environment::Environment) -> config::Config {
     match build_config(env) {
-        Ok(config) => config,
+        Ok(config) => {
+            for err in env.unread_file_keys() {
+                log::warn!("{}", err);
+            }
+
+            config
+        }
+
+        Err(errors) => {
+            for err in errors {
+                log::error!("Failed to build config: {}", err);
+            }
 
-        Err(err) => {
-            log::error!("Failed to build config: {}", err);
             process::exit(1)
         }
     }
 }
 
-fn build_config(env: &environment::Environment) -> Result<config::Config, environment::Error> {
-    let server = build_server_config(env)?;
-    let api = build_api_config(env)?;
-    let unix_socket = build_unix_socket_config(env)?;
-    let container = build_container_config(env)?;
-    let run = build_run_config(env)?;
-    let debug = build_debug_config(env)?;
+// Every key is read even if another one failed, so all errors are reported at once
+fn build_config(env: &environment::Environment) -> Result<config::Config, Vec<environment::Error>> {
+    let mut reader = environment::Reader::new(env);
 
-    Ok(config::Config {
+    let server = build_server_config(&mut reader);
+    let auth = build_auth_config(&mut reader);
+    let rate_limit = build_rate_limit_config(&mut reader);
+    let unix_socket = build_unix_socket_config(&mut reader);
+    let debug = build_debug_config(&mut reader);
+    let container = build_container_config(&mut reader, debug.as_ref());
+    let profiles = build_profiles_config(&mut reader);
+    let allowlist = build_allowlist_config(&mut reader);
+    let images = build_images_config(&mut reader, allowlist.as_ref());
+    let languages = build_languages_config(&mut reader);
+    let run = build_run_config(&mut reader);
+    let queue = build_queue_config(&mut reader, server.as_ref());
+    let limits = build_limits_config(&mut reader, run.as_ref(), container.as_ref());
+    let reaper = build_reaper_config(&mut reader);
+    let pool = build_pool_config(&mut reader, reaper.as_ref());
+    check_reaper_max_age(
+        &mut reader,
+        reaper.as_ref(),
+        run.as_ref(),
+        limits.as_ref(),
+        profiles.as_ref(),
+        pool.as_ref(),
+    );
+    let health = build_health_config(&mut reader);
+    let metrics = build_metrics_config(&mut reader, server.as_ref());
+    let jobs = build_jobs_config(&mut reader);
+    let batch = build_batch_config(&mut reader);
+
+    let errors = reader.into_errors();
+
+    match (
         server,
-        api,
+        auth,
+        rate_limit,
         unix_socket,
         container,
+        profiles,
+        allowlist,
+        images,
+        languages,
         run,
+        queue,
+        pool,
+        limits,
         debug,
-    })
+        reaper,
+        health,
+        metrics,
+        jobs,
+        batch,
+    ) {
+        (
+            Some(server),
+            Some(auth),
+            Some(rate_limit),
+            Some(unix_socket),
+            Some(container),
+            Some(profiles),
+            Some(allowlist),
+            Some(images),
+            Some(languages),
+            Some(run),
+            Some(queue),
+            Some(pool),
+            Some(limits),
+            Some(debug),
+            Some(reaper),
+            Some(health),
+            Some(metrics),
+            Some(jobs),
+            Some(batch),
+        ) if errors.is_empty() => Ok(config::Config {
+            server,
+            auth,
+            rate_limit,
+            unix_socket,
+            container,
+            profiles,
+            allowlist,
+            images,
+            languages,
+            run,
+            queue,
+            pool,
+            limits,
+            debug,
+            reaper,
+            health,
+            metrics,
+            jobs,
+            batch,
+        }),
+
+        _ => Err(errors),
+    }
 }
 
-fn build_server_config(
-    env: &environment::Environment,
-) -> Result<config::ServerConfig, environment::Error> {
-    let listen_addr = environment::lookup(env, "SERVER_LISTEN_ADDR")?;
-    let listen_port = environment::lookup(env, "SERVER_LISTEN_PORT")?;
-    let worker_threads = environment::lookup(env, "SERVER_WORKER_THREADS")?;
+fn build_server_config(env: &mut environment::Reader) -> Option<config::ServerConfig> {
+    let listen_addr = env.lookup("SERVER_LISTEN_ADDR");
+    let listen_port = env.lookup("SERVER_LISTEN_PORT");
+    let worker_threads = env.lookup("SERVER_WORKER_THREADS");
+    let shutdown_grace_period = env.lookup_or("SHUTDOWN_GRACE_PERIOD", 30);
 
-    Ok(config::ServerConfig {
-        listen_addr,
-        listen_port,
-        worker_threads,
+    Some(config::ServerConfig {
+        listen_addr: listen_addr?,
+        listen_port: listen_port?,
+        worker_threads: worker_threads?,
+        shutdown_grace_period: Duration::from_secs(shutdown_grace_period),
     })
 }
 
-fn build_api_config(env: &environment::Environment) -> Result<api::ApiConfig, environment::Error> {
-    let access_token = environment::lookup(env, "API_ACCESS_TOKEN")?;
+fn build_auth_config(env: &mut environment::Reader) -> Option<auth::Config> {
+    let access_token = env.lookup_optional("API_ACCESS_TOKEN");
+    let access_token = env.validate("API_ACCESS_TOKEN", access_token, |token: &String| {
+        if token.is_empty() {
+            Err("Must not be empty".to_string())
+        } else {
+            Ok(())
+        }
+    });
+    let tokens_file = env.lookup_optional("API_ACCESS_TOKENS_FILE");
+
+    match (&access_token, &tokens_file) {
+        // An invalid token has been reported already
+        (None, None) if !env.is_set("API_ACCESS_TOKEN") => {
+            env.error(environment::Error::KeyNotFound(
+                "API_ACCESS_TOKEN".to_string(),
+            ));
+            None
+        }
+
+        _ => Some(auth::Config {
+            access_token,
+            tokens_file,
+        }),
+    }
+}
+
+fn build_rate_limit_config(env: &mut environment::Reader) -> Option<rate_limit::Config> {
+    let requests_per_second = env.lookup_optional("RATE_LIMIT_REQUESTS_PER_SECOND");
+    let burst = env.lookup_optional("RATE_LIMIT_BURST");
+    let max_concurrent_runs = env.lookup_optional("RATE_LIMIT_MAX_CONCURRENT_RUNS");
 
-    Ok(api::ApiConfig { access_token })
+    let requests_per_second = env.validate(
+        "RATE_LIMIT_REQUESTS_PER_SECOND",
+        requests_per_second,
+        |rate| rate_limit::validate_requests_per_second(*rate),
+    );
+    let burst = env.validate("RATE_LIMIT_BURST", burst, |burst| {
+        rate_limit::validate_burst(*burst)
+    });
+    let max_concurrent_runs = env.validate(
+        "RATE_LIMIT_MAX_CONCURRENT_RUNS",
+        max_concurrent_runs,
+        |max| rate_limit::validate_max_concurrent_runs(*max),
+    );
+
+    Some(rate_limit::Config {
+        limits: rate_limit::RateLimit {
+            requests_per_second,
+            burst,
+            max_concurrent_runs,
+        },
+    })
 }
 
-fn build_unix_socket_config(
-    env: &environment::Environment,
-) -> Result<unix_stream::Config, environment::Error> {
-    let path = environment::lookup(env, "DOCKER_UNIX_SOCKET_PATH")?;
-    let read_timeout = environment::lookup(env, "DOCKER_UNIX_SOCKET_READ_TIMEOUT")?;
-    let write_timeout = environment::lookup(env, "DOCKER_UNIX_SOCKET_WRITE_TIMEOUT")?;
+fn build_unix_socket_config(env: &mut environment::Reader) -> Option<unix_stream::Config> {
+    let path: Option<PathBuf> = env.lookup_optional("DOCKER_UNIX_SOCKET_PATH");
+    let docker_host = env.lookup_optional("DOCKER_HOST");
+    let read_timeout = env.lookup("DOCKER_UNIX_SOCKET_READ_TIMEOUT");
+    let write_timeout = env.lookup("DOCKER_UNIX_SOCKET_WRITE_TIMEOUT");
+
+    // An explicit socket path takes precedence over DOCKER_HOST, which is often set for the docker cli
+    let address = match (path, docker_host) {
+        (Some(path), _) => unix_stream::Address::Unix(path),
+
+        (None, Some(unix_stream::Address::Tcp { host, port, .. })) => {
+            let tls = build_tls_config(env)?;
+            let allow_plaintext = env.lookup_or("DOCKER_ALLOW_PLAINTEXT_TCP", false);
+
+            // The daemon api gives full control over the host, it's only sent in plaintext on request
+            match (&tls, allow_plaintext) {
+                (None, false) => {
+                    let err = env.parse_error(
+                        "DOCKER_HOST",
+                        "A tcp address requires tls, set DOCKER_TLS_VERIFY or DOCKER_CERT_PATH, \
+                         or set DOCKER_ALLOW_PLAINTEXT_TCP to connect without tls"
+                            .to_string(),
+                    );
+                    env.error(err);
+                    return None;
+                }
 
-    Ok(unix_stream::Config {
-        path,
-        read_timeout: Duration::from_secs(read_timeout),
-        write_timeout: Duration::from_secs(write_timeout),
+                (None, true) => {
+                    log::warn!(
+                        "Connecting to the docker daemon at {}:{} without tls",
+                        host,
+                        port
+                    );
+                }
+
+                (Some(_), _) => {}
+            }
+
+            unix_stream::Address::Tcp { host, port, tls }
+        }
+
+        (None, Some(address)) => address,
+
+        // An invalid DOCKER_HOST is already reported
+        (None, None) if env.is_set("DOCKER_HOST") => return None,
+
+        (None, None) => {
+            env.error(environment::Error::KeyNotFound(
+                "DOCKER_UNIX_SOCKET_PATH".to_string(),
+            ));
+            return None;
+        }
+    };
+
+    Some(unix_stream::Config {
+        address,
+        read_timeout: Duration::from_secs(read_timeout?),
+        write_timeout: Duration::from_secs(write_timeout?),
     })
 }
 
+// Tls is used when DOCKER_TLS_VERIFY, DOCKER_CERT_PATH or one of the certificate files is set.
+// The files default to ca.pem, cert.pem and key.pem in DOCKER_CERT_PATH, like the docker cli.
+// Returns None if the tls config is invalid.
+fn build_tls_config(env: &mut environment::Reader) -> Option<Option<tls::Config>> {
+    let verify: Option<String> = env.lookup_optional("DOCKER_TLS_VERIFY");
+    let cert_path: Option<PathBuf> = env.lookup_optional("DOCKER_CERT_PATH");
+    let ca_file: Option<PathBuf> = env.lookup_optional("DOCKER_TLS_CA_FILE");
+    let cert_file: Option<PathBuf> = env.lookup_optional("DOCKER_TLS_CERT_FILE");
+    let key_file: Option<PathBuf> = env.lookup_optional("DOCKER_TLS_KEY_FILE");
+
+    let verify = verify.is_some_and(|value| !matches!(value.as_str(), "" | "0" | "false"));
+
+    let enabled = verify
+        || cert_path.is_some()
+        || ca_file.is_some()
+        || cert_file.is_some()
+        || key_file.is_some();
+
+    if !enabled {
+        return Some(None);
+    }
+
+    // A file that is not set and has no default in DOCKER_CERT_PATH is reported under its own key
+    let tls_file = |file: Option<PathBuf>, key: &str, name: &str| {
+        file.or_else(|| cert_path.as_ref().map(|path| path.join(name)))
+            .ok_or_else(|| environment::Error::KeyNotFound(key.to_string()))
+    };
+
+    let ca_file = tls_file(ca_file, "DOCKER_TLS_CA_FILE", "ca.pem");
+    let cert_file = tls_file(cert_file, "DOCKER_TLS_CERT_FILE", "cert.pem");
+    let key_file = tls_file(key_file, "DOCKER_TLS_KEY_FILE", "key.pem");
+
+    let ca_file = env.collect(ca_file);
+    let cert_file = env.collect(cert_file);
+    let key_file = env.collect(key_file);
+    let (ca_file, cert_file, key_file) = (ca_file?, cert_file?, key_file?);
+
+    let result = tls::load(ca_file.clone(), cert_file.clone(), key_file.clone())
+        .map(Some)
+        .map_err(|err| {
+            let key = match (&err, err.path()) {
+                (_, Some(path)) if path == ca_file => "DOCKER_TLS_CA_FILE",
+                (_, Some(path)) if path == key_file => "DOCKER_TLS_KEY_FILE",
+                (tls::Error::InvalidCa(_), _) => "DOCKER_TLS_CA_FILE",
+
+                // The client certificate doesn't match the key
+                _ => "DOCKER_TLS_CERT_FILE",
+            };
+
+            env.parse_error(key, err.to_string())
+        });
+
+    env.collect(result)
+}
+
 fn build_container_config(
-    env: &environment::Environment,
-) -> Result<run::ContainerConfig, environment::Error> {
-    let hostname = environment::lookup(env, "DOCKER_CONTAINER_HOSTNAME")?;
-    let user = environment::lookup(env, "DOCKER_CONTAINER_USER")?;
-    let memory = environment::lookup(env, "DOCKER_CONTAINER_MEMORY")?;
-    let network_disabled = environment::lookup(env, "DOCKER_CONTAINER_NETWORK_DISABLED")?;
-    let ulimit_nofile_soft = environment::lookup(env, "DOCKER_CONTAINER_ULIMIT_NOFILE_SOFT")?;
-    let ulimit_nofile_hard = environment::lookup(env, "DOCKER_CONTAINER_ULIMIT_NOFILE_HARD")?;
-    let ulimit_nproc_soft = environment::lookup(env, "DOCKER_CONTAINER_ULIMIT_NPROC_SOFT")?;
-    let ulimit_nproc_hard = environment::lookup(env, "DOCKER_CONTAINER_ULIMIT_NPROC_HARD")?;
-    let cap_add = environment::lookup(env, "DOCKER_CONTAINER_CAP_ADD").unwrap_or_default();
-    let cap_drop = environment::lookup(env, "DOCKER_CONTAINER_CAP_DROP").unwrap_or_default();
-    let readonly_rootfs =
-        environment::lookup(env, "DOCKER_CONTAINER_READONLY_ROOTFS").unwrap_or(false);
-    let tmp_dir_path: Option<String> =
-        environment::lookup_optional(env, "DOCKER_CONTAINER_TMP_DIR_PATH")?;
-    let tmp_dir_options = environment::lookup(env, "DOCKER_CONTAINER_TMP_DIR_OPTIONS")
-        .unwrap_or_else(|_| "rw,noexec,nosuid,size=65536k".to_string());
-    let work_dir_path: Option<String> =
-        environment::lookup_optional(env, "DOCKER_CONTAINER_WORK_DIR_PATH")?;
-    let work_dir_options = environment::lookup(env, "DOCKER_CONTAINER_WORK_DIR_OPTIONS")
-        .unwrap_or_else(|_| "rw,exec,nosuid,size=131072k".to_string());
-
-    Ok(run::ContainerConfig {
-        hostname,
-        user,
-        memory,
-        network_disabled,
-        ulimit_nofile_soft,
-        ulimit_nofile_hard,
-        ulimit_nproc_soft,
-        ulimit_nproc_hard,
+    env: &mut environment::Reader,
+    debug: Option<&debug::Config>,
+) -> Option<run::ContainerConfig> {
+    let hostname = env.lookup("DOCKER_CONTAINER_HOSTNAME");
+    let user = env.lookup("DOCKER_CONTAINER_USER");
+    let memory = env.lookup("DOCKER_CONTAINER_MEMORY");
+    let network_disabled = env.lookup("DOCKER_CONTAINER_NETWORK_DISABLED");
+    let ulimit_nofile_soft = env.lookup("DOCKER_CONTAINER_ULIMIT_NOFILE_SOFT");
+    let ulimit_nofile_hard = env.lookup("DOCKER_CONTAINER_ULIMIT_NOFILE_HARD");
+    let ulimit_nproc_soft = env.lookup("DOCKER_CONTAINER_ULIMIT_NPROC_SOFT");
+    let ulimit_nproc_hard = env.lookup("DOCKER_CONTAINER_ULIMIT_NPROC_HARD");
+    let cap_add = env.lookup_or("DOCKER_CONTAINER_CAP_ADD", String::new());
+    let cap_drop = env.lookup_or("DOCKER_CONTAINER_CAP_DROP", String::new());
+    let readonly_rootfs = env.lookup_or("DOCKER_CONTAINER_READONLY_ROOTFS", false);
+    let tmp_dir_path: Option<String> = env.lookup_optional("DOCKER_CONTAINER_TMP_DIR_PATH");
+    let tmp_dir_options = env.lookup_or(
+        "DOCKER_CONTAINER_TMP_DIR_OPTIONS",
+        "rw,noexec,nosuid,size=65536k".to_string(),
+    );
+    let work_dir_path: Option<String> = env.lookup_optional("DOCKER_CONTAINER_WORK_DIR_PATH");
+    let work_dir_options = env.lookup_or(
+        "DOCKER_CONTAINER_WORK_DIR_OPTIONS",
+        "rw,exec,nosuid,size=131072k".to_string(),
+    );
+    let runtime = env.lookup_optional("DOCKER_CONTAINER_RUNTIME");
+    let instance_id = env
+        .lookup_optional("INSTANCE_ID")
+        .unwrap_or_else(reaper::default_instance_id);
+
+    Some(run::ContainerConfig {
+        hostname: hostname?,
+        user: user?,
+        memory: memory?,
+        network_disabled: network_disabled?,
+        ulimit_nofile_soft: ulimit_nofile_soft?,
+        ulimit_nofile_hard: ulimit_nofile_hard?,
+        ulimit_nproc_soft: ulimit_nproc_soft?,
+        ulimit_nproc_hard: ulimit_nproc_hard?,
         cap_add: environment::space_separated_string(cap_add),
         cap_drop: environment::space_separated_string(cap_drop),
         readonly_rootfs,
@@ -217,21 +1347,347 @@ fn build_container_config(
             path,
             options: work_dir_options,
         }),
+        runtime,
+        instance_id,
+        keep_container: debug?.keep_container,
+    })
+}
+
+fn build_profiles_config(env: &mut environment::Reader) -> Option<profiles::Config> {
+    let names = env.lookup_or("PROFILES", String::new());
+
+    // Every profile is read, so the errors of all profiles are reported
+    let profiles: Vec<Option<profiles::Profile>> = environment::space_separated_string(names)
+        .into_iter()
+        .map(|name| build_profile(env, name))
+        .collect();
+
+    let profiles = profiles.into_iter().collect::<Option<_>>()?;
+
+    Some(profiles::Config { profiles })
+}
+
+// Profile keys are prefixed with the upper case profile name, i.e. PROFILE_COMPILED_MEMORY
+fn build_profile(env: &mut environment::Reader, name: String) -> Option<profiles::Profile> {
+    let prefix = format!("PROFILE_{}", name.to_uppercase().replace('-', "_"));
+    let key = |suffix: &str| format!("{}_{}", prefix, suffix);
+
+    let images = env.lookup_or(&key("IMAGES"), String::new());
+    let languages = env.lookup_or(&key("LANGUAGES"), String::new());
+    let memory = env.lookup_optional(&key("MEMORY"));
+    let ulimit_nofile_soft = env.lookup_optional(&key("ULIMIT_NOFILE_SOFT"));
+    let ulimit_nofile_hard = env.lookup_optional(&key("ULIMIT_NOFILE_HARD"));
+    let ulimit_nproc_soft = env.lookup_optional(&key("ULIMIT_NPROC_SOFT"));
+    let ulimit_nproc_hard = env.lookup_optional(&key("ULIMIT_NPROC_HARD"));
+    let readonly_rootfs = env.lookup_optional(&key("READONLY_ROOTFS"));
+    let tmp_dir_path: Option<String> = env.lookup_optional(&key("TMP_DIR_PATH"));
+    let tmp_dir_options = env.lookup_or(
+        &key("TMP_DIR_OPTIONS"),
+        "rw,noexec,nosuid,size=65536k".to_string(),
+    );
+    let work_dir_path: Option<String> = env.lookup_optional(&key("WORK_DIR_PATH"));
+    let work_dir_options = env.lookup_or(
+        &key("WORK_DIR_OPTIONS"),
+        "rw,exec,nosuid,size=131072k".to_string(),
+    );
+    let max_execution_time: Option<u64> = env.lookup_optional(&key("MAX_EXECUTION_TIME"));
+    let max_output_size = env.lookup_optional(&key("MAX_OUTPUT_SIZE"));
+
+    let images = environment::space_separated_string(images);
+    let languages = environment::space_separated_string(languages);
+
+    if images.is_empty() && languages.is_empty() {
+        env.error(env.parse_error(
+            &key("IMAGES"),
+            format!("Profile {} has no images or languages to match", name),
+        ));
+        return None;
+    }
+
+    Some(profiles::Profile {
+        name,
+        images,
+        languages,
+        overrides: profiles::Overrides {
+            memory,
+            ulimit_nofile_soft,
+            ulimit_nofile_hard,
+            ulimit_nproc_soft,
+            ulimit_nproc_hard,
+            readonly_rootfs,
+            tmp_dir: tmp_dir_path.map(|path| run::Tmpfs {
+                path,
+                options: tmp_dir_options,
+            }),
+            work_dir: work_dir_path.map(|path| run::Tmpfs {
+                path,
+                options: work_dir_options,
+            }),
+            max_execution_time: max_execution_time.map(Duration::from_secs),
+            max_output_size,
+        },
+    })
+}
+
+fn build_allowlist_config(env: &mut environment::Reader) -> Option<allowlist::Config> {
+    let allowlist = env.lookup_or("IMAGE_ALLOWLIST", String::new());
+
+    let entries = environment::space_separated_string(allowlist)
+        .iter()
+        .map(|entry| entry.parse())
+        .collect::<Result<_, String>>()
+        .map_err(|details| env.parse_error("IMAGE_ALLOWLIST", details));
+
+    Some(allowlist::Config {
+        entries: env.collect(entries)?,
+    })
+}
+
+// Images are pulled for run requests, without an allowlist any token could pull any image
+fn build_images_config(
+    env: &mut environment::Reader,
+    allowlist: Option<&allowlist::Config>,
+) -> Option<images::Config> {
+    let pull_policy = env.lookup_or("IMAGE_PULL_POLICY", images::PullPolicy::Never);
+    let pre_pull = env.lookup_or("IMAGE_PRE_PULL", String::new());
+    let pull_timeout = env.lookup_or("IMAGE_PULL_TIMEOUT", 300);
+    let pull_interval = env.lookup_or("IMAGE_PULL_INTERVAL", 300);
+
+    let pull_policy = env.validate("IMAGE_PULL_POLICY", Some(pull_policy), |pull_policy| {
+        let allowlist_missing = allowlist.is_some_and(|allowlist| !allowlist.is_enabled());
+
+        if *pull_policy != images::PullPolicy::Never && allowlist_missing {
+            Err("Pulling images requires IMAGE_ALLOWLIST to be set".to_string())
+        } else {
+            Ok(())
+        }
+    });
+
+    Some(images::Config {
+        pull_policy: pull_policy?,
+        pre_pull: environment::space_separated_string(pre_pull),
+        pull_timeout: Duration::from_secs(pull_timeout),
+        pull_interval: Duration::from_secs(pull_interval),
+    })
+}
+
+fn build_languages_config(env: &mut environment::Reader) -> Option<languages::Config> {
+    let path: Option<PathBuf> = env.lookup_optional("LANGUAGES_FILE");
+
+    match path {
+        Some(path) => {
+            let result = languages::read_file(&path)
+                .map_err(|err| env.parse_error("LANGUAGES_FILE", err.to_string()));
+
+            env.collect(result)
+        }
+
+        None if env.is_set("LANGUAGES_FILE") => None,
+
+        None => Some(languages::Config::default()),
+    }
+}
+
+fn build_run_config(env: &mut environment::Reader) -> Option<run::Limits> {
+    let max_execution_time = env.lookup("RUN_MAX_EXECUTION_TIME");
+    let max_output_size = env.lookup("RUN_MAX_OUTPUT_SIZE");
+
+    Some(run::Limits {
+        max_execution_time: Duration::from_secs(max_execution_time?),
+        max_output_size: max_output_size?,
+    })
+}
+
+// The defaults come from the server config, the keys are read even if it failed
+fn build_queue_config(
+    env: &mut environment::Reader,
+    server: Option<&config::ServerConfig>,
+) -> Option<queue::Config> {
+    let max_concurrent_runs = env.lookup_optional("RUN_QUEUE_MAX_CONCURRENT");
+    let max_concurrent_runs = env.validate(
+        "RUN_QUEUE_MAX_CONCURRENT",
+        max_concurrent_runs,
+        |max: &usize| {
+            if *max >= 1 {
+                Ok(())
+            } else {
+                Err("Must be at least 1, no run could ever start".to_string())
+            }
+        },
+    );
+    let max_depth = env.lookup_or("RUN_QUEUE_MAX_DEPTH", 100);
+    let max_wait = env.lookup_or("RUN_QUEUE_MAX_WAIT", 30);
+
+    Some(queue::Config {
+        max_concurrent_runs: max_concurrent_runs.or(server.map(|server| server.worker_threads))?,
+        max_depth,
+        max_wait: Duration::from_secs(max_wait),
     })
 }
 
-fn build_run_config(env: &environment::Environment) -> Result<run::Limits, environment::Error> {
-    let max_execution_time = environment::lookup(env, "RUN_MAX_EXECUTION_TIME")?;
-    let max_output_size = environment::lookup(env, "RUN_MAX_OUTPUT_SIZE")?;
+fn build_limits_config(
+    env: &mut environment::Reader,
+    run: Option<&run::Limits>,
+    container: Option<&run::ContainerConfig>,
+) -> Option<limits::Config> {
+    let max_execution_time = env.lookup_optional("LIMITS_MAX_EXECUTION_TIME");
+    let max_output_size = env.lookup_optional("LIMITS_MAX_OUTPUT_SIZE");
+    let max_memory = env.lookup_optional("LIMITS_MAX_MEMORY");
+    let max_nproc = env.lookup_optional("LIMITS_MAX_NPROC");
+    let policy = env.lookup_or("LIMITS_OVERRIDE_POLICY", limits::Policy::Clamp);
+
+    let max_execution_time =
+        max_execution_time.or(run.map(|run| run.max_execution_time.as_secs()))?;
+    let max_output_size = max_output_size.or(run.map(|run| run.max_output_size))?;
+    let max_memory = max_memory.or(container.map(|container| container.memory))?;
+    let max_nproc = max_nproc.or(container.map(|container| container.ulimit_nproc_hard))?;
 
-    Ok(run::Limits {
+    Some(limits::Config {
         max_execution_time: Duration::from_secs(max_execution_time),
         max_output_size,
+        max_memory,
+        max_nproc,
+        policy,
+    })
+}
+
+fn build_debug_config(env: &mut environment::Reader) -> Option<debug::Config> {
+    let keep_container = env.lookup_or("DEBUG_KEEP_CONTAINER", false);
+
+    Some(debug::Config { keep_container })
+}
+
+fn build_reaper_config(env: &mut environment::Reader) -> Option<reaper::Config> {
+    let max_age = env.lookup_or("REAPER_MAX_AGE", 600);
+    let interval = env.lookup_or("REAPER_INTERVAL", 60);
+
+    Some(reaper::Config {
+        max_age: Duration::from_secs(max_age),
+        interval: (interval > 0).then(|| Duration::from_secs(interval)),
+    })
+}
+
+// Containers of running programs must not be reaped. A pooled container can be idle
+// for max idle before it's claimed and the program can run for the longest execution
+// time any profile or override allows.
+fn check_reaper_max_age(
+    env: &mut environment::Reader,
+    reaper: Option<&reaper::Config>,
+    run: Option<&run::Limits>,
+    limits: Option<&limits::Config>,
+    profiles: Option<&profiles::Config>,
+    pool: Option<&pool::Config>,
+) {
+    if let (Some(reaper), Some(run), Some(limits), Some(profiles), Some(pool)) =
+        (reaper, run, limits, profiles, pool)
+    {
+        let longest_execution_time = profiles
+            .profiles
+            .iter()
+            .filter_map(|profile| profile.overrides.max_execution_time)
+            .chain([run.max_execution_time, limits.max_execution_time])
+            .max()
+            .unwrap_or_default();
+
+        let max_idle = if pool.sizes.is_empty() {
+            Duration::from_secs(0)
+        } else {
+            pool.max_idle
+        };
+
+        let longest_lifetime = longest_execution_time + max_idle;
+
+        if reaper.max_age <= longest_lifetime {
+            let err = env.parse_error(
+                "REAPER_MAX_AGE",
+                format!(
+                    "Must be higher than the longest allowed execution time{}: {} seconds",
+                    if pool.sizes.is_empty() {
+                        ""
+                    } else {
+                        " plus POOL_MAX_IDLE"
+                    },
+                    longest_lifetime.as_secs()
+                ),
+            );
+
+            env.error(err);
+        }
+    }
+}
+
+fn build_pool_config(
+    env: &mut environment::Reader,
+    reaper: Option<&reaper::Config>,
+) -> Option<pool::Config> {
+    let sizes = env.lookup_or("POOL_SIZES", String::new());
+    let max_idle = env.lookup_optional("POOL_MAX_IDLE");
+
+    let sizes = environment::space_separated_string(sizes)
+        .iter()
+        .map(|entry| parse_pool_size(entry))
+        .collect::<Result<_, String>>()
+        .map_err(|details| env.parse_error("POOL_SIZES", details));
+
+    let sizes = env.collect(sizes)?;
+    let max_idle = max_idle.or(reaper.map(|reaper| reaper.max_age.as_secs() / 2))?;
+
+    Some(pool::Config {
+        sizes,
+        max_idle: Duration::from_secs(max_idle),
+    })
+}
+
+// Parses an image=size entry
+fn parse_pool_size(entry: &str) -> Result<(String, usize), String> {
+    let (image, size) = entry
+        .rsplit_once('=')
+        .ok_or_else(|| format!("Expected image=size, got: {}", entry))?;
+
+    let size = size
+        .parse()
+        .map_err(|err| format!("Invalid pool size for {}: {}", image, err))?;
+
+    Ok((image.to_string(), size))
+}
+
+fn build_health_config(env: &mut environment::Reader) -> Option<api::health::Config> {
+    let required_images = env.lookup_or("READY_REQUIRED_IMAGES", String::new());
+
+    Some(api::health::Config {
+        required_images: environment::space_separated_string(required_images),
     })
 }
 
-fn build_debug_config(env: &environment::Environment) -> Result<debug::Config, environment::Error> {
-    let keep_container = environment::lookup(env, "DEBUG_KEEP_CONTAINER").unwrap_or(false);
+fn build_metrics_config(
+    env: &mut environment::Reader,
+    server: Option<&config::ServerConfig>,
+) -> Option<config::MetricsConfig> {
+    let listen_addr = env.lookup_optional("METRICS_LISTEN_ADDR");
+    let listen_port = env.lookup_optional("METRICS_LISTEN_PORT");
+
+    Some(config::MetricsConfig {
+        listen_addr: listen_addr.or_else(|| server.map(|server| server.listen_addr.clone()))?,
+        listen_port,
+    })
+}
 
-    Ok(debug::Config { keep_container })
+fn build_jobs_config(env: &mut environment::Reader) -> Option<jobs::Config> {
+    let result_ttl = env.lookup_or("JOBS_RESULT_TTL", 300);
+    let max_jobs = env.lookup_or("JOBS_MAX_STORED", 1000);
+
+    Some(jobs::Config {
+        result_ttl: Duration::from_secs(result_ttl),
+        max_jobs,
+    })
+}
+
+fn build_batch_config(env: &mut environment::Reader) -> Option<api::run_batch::Config> {
+    let max_parallelism = env.lookup_or("BATCH_MAX_PARALLELISM", 4);
+    let max_size = env.lookup_or("BATCH_MAX_SIZE", 50);
+
+    Some(api::run_batch::Config {
+        max_parallelism,
+        max_size,
+    })
 }